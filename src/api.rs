@@ -0,0 +1,188 @@
+//! JSON API spoken over the config server's websocket.
+//!
+//! It is versioned separately from the peer wire protocol so internal
+//! events can change without breaking config clients. On connect the
+//! server sends `{"Hello": {"version": 1}}` followed by a `Cluster` event.
+//!
+//! Clients send requests with an id of their choosing:
+//!
+//! ```text
+//! {"id": 1, "method": {"SetEdge": {"screen": 0, "edge": "right", "neighbour": 1}}}
+//! ```
+//!
+//! and get back exactly one response carrying the same id:
+//!
+//! ```text
+//! {"Response": {"id": 1, "result": {"Ok": []}}}
+//! {"Response": {"id": 1, "result": {"Error": "No screen 1"}}}
+//! ```
+//!
+//! Edges are `top`, `right`, `bottom` or `left`, and edge policies are
+//! `cross` or `block`. Host ids are hex strings, as they don't fit in a
+//! JavaScript number. Everything else the server sends is an `Event`.
+
+use cluster::{Cluster, Edge, EdgePolicy, Index, EDGES};
+use route::RouteStatus;
+
+pub const API_VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Request {
+    pub id: u64,
+    pub method: Method,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub enum Method {
+    GetStatus,
+    GetCluster,
+    RenameScreen { screen: Index, name: String },
+    RemoveScreen { screen: Index },
+    /// Let a removed screen join the cluster again
+    ReadmitScreen { host_id: String },
+    /// Link an edge to a neighbour, or unlink it with a null neighbour
+    SetEdge { screen: Index, edge: String, neighbour: Option<Index> },
+    SetEdgePolicy { screen: Index, edge: String, policy: String },
+    /// Pointer sensitivity and acceleration exponent, null leaves one unchanged
+    SetPointer { screen: Index, scale: Option<f64>, accel: Option<f64> },
+}
+
+/// Everything sent to config clients
+#[derive(Serialize, Deserialize, Debug)]
+pub enum Message {
+    Hello(Hello),
+    /// The id is missing if the request couldn't be parsed
+    Response { id: Option<u64>, result: Reply },
+    Event(Event),
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Hello {
+    pub version: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub enum Reply {
+    Ok,
+    Status(Status),
+    Cluster(ClusterView),
+    Error(String),
+}
+
+/// Live updates pushed without a request
+#[derive(Serialize, Deserialize, Debug)]
+pub enum Event {
+    Cluster(ClusterView),
+    Focus(FocusStatus),
+    PeerOnline(PeerStatus),
+    PeerOffline(PeerStatus),
+    /// Reachability and round trip time of every peer address
+    Routes(Vec<RouteStatus>),
+    Error(String),
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Status {
+    pub state: String,
+    pub focus: FocusStatus,
+    pub locked: bool,
+    pub relative: bool,
+    pub routes: Vec<RouteStatus>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct FocusStatus {
+    pub screen: Index,
+    pub x: i32,
+    pub y: i32,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PeerStatus {
+    pub screen: Index,
+    pub name: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ClusterView {
+    pub local_screen: Index,
+    pub focused_screen: Index,
+    pub screens: Vec<ScreenView>,
+    pub removed: Vec<RemovedView>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RemovedView {
+    pub host_id: String,
+    pub name: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ScreenView {
+    pub name: String,
+    pub width: i32,
+    pub height: i32,
+    pub addrs: Vec<String>,
+    pub edges: EdgesView<Option<Index>>,
+    pub policies: EdgesView<String>,
+    pub scale: f64,
+    pub accel: f64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct EdgesView<T> {
+    pub top: T,
+    pub right: T,
+    pub bottom: T,
+    pub left: T,
+}
+
+impl<T> EdgesView<T> {
+    fn new<F>(f: F) -> Self where F: Fn(Edge) -> T {
+        EdgesView {
+            top: f(EDGES[0]),
+            right: f(EDGES[1]),
+            bottom: f(EDGES[2]),
+            left: f(EDGES[3]),
+        }
+    }
+}
+
+impl ClusterView {
+    pub fn new(cluster: &Cluster) -> Self {
+        ClusterView {
+            local_screen: cluster.local_index(),
+            focused_screen: cluster.focused_screen_index(),
+            screens: cluster.get_screens().iter().map(|screen| {
+                let (width, height) = screen.size();
+                let (scale, accel) = screen.pointer();
+                ScreenView {
+                    name: screen.name().to_owned(),
+                    width: width,
+                    height: height,
+                    addrs: screen.addrs().iter().map(|addr| format!("{}", addr)).collect(),
+                    edges: EdgesView::new(|edge| screen.edge(edge)),
+                    policies: EdgesView::new(|edge| format!("{}", screen.policy(edge))),
+                    scale: scale,
+                    accel: accel,
+                }
+            }).collect(),
+            removed: cluster.removed_screens().iter().map(|removed| RemovedView {
+                host_id: format!("{:016x}", removed.host_id),
+                name: removed.name.clone(),
+            }).collect(),
+        }
+    }
+}
+
+pub fn parse_edge(edge: &str) -> Result<Edge, String> {
+    edge.parse().map_err(|_| format!("Unknown edge: {}", edge))
+}
+
+pub fn parse_host_id(host_id: &str) -> Result<u64, String> {
+    u64::from_str_radix(host_id, 16).map_err(|_| format!("Invalid host id: {}", host_id))
+}
+
+pub fn parse_policy(policy: &str) -> Result<EdgePolicy, String> {
+    policy.parse().map_err(|_| format!("Unknown edge policy: {}", policy))
+}
@@ -0,0 +1,251 @@
+use std::str::FromStr;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// How to choose between hosts sending input at the same time
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize, Debug)]
+pub enum Policy {
+    /// The controlling host keeps control until it goes idle
+    FirstWins,
+    /// As first-wins, but a host with a higher priority takes control straight away
+    Priority,
+    /// As first-wins, but a host can explicitly take control, by focusing
+    /// a screen with `elemeld ctl focus` or reclaiming it with local input
+    Takeover,
+}
+
+impl FromStr for Policy {
+    type Err = ();
+
+    fn from_str(val: &str) -> Result<Self, ()> {
+        match val {
+            "first-wins" => Ok(Policy::FirstWins),
+            "priority" => Ok(Policy::Priority),
+            "takeover" => Ok(Policy::Takeover),
+            _ => Err(()),
+        }
+    }
+}
+
+/// The policy and idle timeout, shared with the layout
+/// so every host judges claims the same way
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize, Debug)]
+pub struct Rules {
+    pub policy: Policy,
+    /// Time without input before the controlling host gives up control
+    pub idle_ms: u64,
+}
+
+/// Sent with every focus change and, while input is being
+/// forwarded, periodically to show the host is still active
+#[derive(Clone, Copy, Serialize, Deserialize, Debug)]
+pub struct Claim {
+    pub host_id: u64,
+    pub priority: i32,
+    /// Control was taken explicitly rather than by ordinary input
+    pub takeover: bool,
+    /// When the host's current run of control began, in milliseconds
+    /// since the Unix epoch. Orders claims made at the same time.
+    pub since: u64,
+}
+
+impl Claim {
+    /// Whether the claim wins against the controller's. Every host orders claims
+    /// the same way, so competing claims settle on the same controller whatever
+    /// order they arrive in. Ties are broken by the earlier claim, then host id.
+    fn beats(&self, controller: &Claim, policy: Policy) -> bool {
+        let earlier = (self.since, self.host_id) < (controller.since, controller.host_id);
+        match policy {
+            Policy::FirstWins => earlier,
+            Policy::Priority => self.priority > controller.priority ||
+                (self.priority == controller.priority && earlier),
+            Policy::Takeover => match (self.takeover, controller.takeover) {
+                (true, false) => true,
+                (false, true) => false,
+                // The latest takeover wins
+                (true, true) => !earlier,
+                (false, false) => earlier,
+            },
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Controller {
+    claim: Claim,
+    active: Instant,
+}
+
+/// Tracks which host controls focus
+pub struct Arbiter {
+    rules: Rules,
+    controller: Option<Controller>,
+}
+
+impl Arbiter {
+    pub fn new(rules: Rules) -> Self {
+        Arbiter {
+            rules: rules,
+            controller: None,
+        }
+    }
+
+    pub fn set_rules(&mut self, rules: Rules) {
+        if rules != self.rules {
+            info!("Arbitration policy {:?}, idle after {} ms", rules.policy, rules.idle_ms);
+            self.rules = rules;
+        }
+    }
+
+    /// The host that controls focus, unless it has gone idle
+    pub fn controller(&self) -> Option<u64> {
+        self.active().map(|controller| controller.claim.host_id)
+    }
+
+    fn active(&self) -> Option<Controller> {
+        let idle = Duration::from_millis(self.rules.idle_ms);
+        self.controller.and_then(|controller| {
+            if controller.active.elapsed() < idle { Some(controller) } else { None }
+        })
+    }
+
+    /// A claim for the local host, continuing its run of control if it has one
+    pub fn local_claim(&self, host_id: u64, priority: i32, takeover: bool) -> Claim {
+        match self.active() {
+            Some(controller) if controller.claim.host_id == host_id && !takeover => Claim {
+                priority: priority,
+                ..controller.claim
+            },
+            _ => Claim {
+                host_id: host_id,
+                priority: priority,
+                takeover: takeover,
+                since: now_ms(),
+            },
+        }
+    }
+
+    /// Whether a claim would be accepted, without recording it
+    pub fn allows(&self, claim: &Claim) -> bool {
+        match self.active() {
+            Some(controller) => controller.claim.host_id == claim.host_id ||
+                claim.beats(&controller.claim, self.rules.policy),
+            None => true,
+        }
+    }
+
+    /// Record a claim if it's allowed, returning whether it was
+    pub fn claim(&mut self, claim: &Claim) -> bool {
+        if !self.allows(claim) {
+            return false;
+        }
+
+        self.record(claim);
+        true
+    }
+
+    /// Make the claim's host the controller regardless of the policy
+    pub fn record(&mut self, claim: &Claim) {
+        if self.controller() != Some(claim.host_id) {
+            info!("Host {:016x} now controls focus", claim.host_id);
+        }
+
+        self.controller = Some(Controller {
+            claim: *claim,
+            active: Instant::now(),
+        });
+    }
+
+    /// Forget the controller if it's the given host
+    pub fn release(&mut self, host_id: u64) {
+        if self.controller.map_or(false, |controller| controller.claim.host_id == host_id) {
+            self.controller = None;
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.controller = None;
+    }
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH)
+        .map(|time| time.as_secs() * 1000 + time.subsec_nanos() as u64 / 1000000)
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Arbiter, Claim, Policy, Rules};
+
+    fn claim(host_id: u64, priority: i32, takeover: bool, since: u64) -> Claim {
+        Claim { host_id: host_id, priority: priority, takeover: takeover, since: since }
+    }
+
+    #[test]
+    fn first_wins_prefers_the_earlier_claim() {
+        let first = claim(2, 0, false, 100);
+        let second = claim(1, 0, false, 200);
+        assert!(first.beats(&second, Policy::FirstWins));
+        assert!(!second.beats(&first, Policy::FirstWins));
+    }
+
+    #[test]
+    fn priority_prefers_the_higher_priority() {
+        let high = claim(1, 5, false, 200);
+        let low = claim(2, 1, false, 100);
+        assert!(high.beats(&low, Policy::Priority));
+        assert!(!low.beats(&high, Policy::Priority));
+
+        // Equal priorities fall back to the earlier claim
+        let late = claim(3, 5, false, 300);
+        assert!(high.beats(&late, Policy::Priority));
+        assert!(!late.beats(&high, Policy::Priority));
+    }
+
+    #[test]
+    fn takeover_prefers_the_latest_takeover() {
+        let ordinary = claim(1, 0, false, 100);
+        let takeover = claim(2, 0, true, 200);
+        let later = claim(3, 0, true, 300);
+        assert!(takeover.beats(&ordinary, Policy::Takeover));
+        assert!(!ordinary.beats(&takeover, Policy::Takeover));
+        assert!(later.beats(&takeover, Policy::Takeover));
+        assert!(!takeover.beats(&later, Policy::Takeover));
+
+        // Without takeovers it's first-wins
+        let late = claim(4, 0, false, 400);
+        assert!(ordinary.beats(&late, Policy::Takeover));
+        assert!(!late.beats(&ordinary, Policy::Takeover));
+    }
+
+    #[test]
+    fn ties_are_broken_by_host_id() {
+        let low = claim(1, 0, false, 100);
+        let high = claim(2, 0, false, 100);
+        for &policy in &[Policy::FirstWins, Policy::Priority] {
+            assert!(low.beats(&high, policy));
+            assert!(!high.beats(&low, policy));
+        }
+
+        let low = Claim { takeover: true, ..low };
+        let high = Claim { takeover: true, ..high };
+        assert!(high.beats(&low, Policy::Takeover));
+        assert!(!low.beats(&high, Policy::Takeover));
+    }
+
+    #[test]
+    fn controller_keeps_control_until_idle() {
+        let mut arbiter = Arbiter::new(Rules { policy: Policy::FirstWins, idle_ms: 60000 });
+        assert!(arbiter.claim(&claim(1, 0, false, 100)));
+        assert!(!arbiter.claim(&claim(2, 0, false, 200)));
+        assert!(arbiter.claim(&claim(1, 0, false, 300)));
+        assert_eq!(arbiter.controller(), Some(1));
+
+        arbiter.release(1);
+        assert!(arbiter.claim(&claim(2, 0, false, 200)));
+
+        arbiter.set_rules(Rules { policy: Policy::FirstWins, idle_ms: 0 });
+        assert_eq!(arbiter.controller(), None);
+        assert!(arbiter.allows(&claim(1, 0, false, 400)));
+    }
+}
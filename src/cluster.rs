@@ -1,17 +1,64 @@
 use io::*;
+use arbiter::Rules;
 use util;
 
 use serde;
-use std::net::{SocketAddr, SocketAddrV4, SocketAddrV6, IpAddr};
+use std::{fmt, cmp, mem};
+use std::str::FromStr;
+use std::net::{SocketAddr, SocketAddrV6};
 
 pub type Index = u8;
 
+/// Toggles relative pointer mode while a remote screen is focused
+pub const RELATIVE_TOGGLE_KEY: u64 = 0xff14; // XK_Scroll_Lock
+
+/// The screens and focus shared with peers
 #[derive(Clone, Serialize, Deserialize, Debug)]
-pub struct Cluster {
+pub struct Layout {
     local_screen: Index,
     screens: Vec<Screen>,
     focus: Focus,
     selections: Vec<Index>,
+    /// Screens removed from the cluster, so they aren't merged back in until readmitted
+    removed: Vec<Removed>,
+    rules: Rules,
+}
+
+/// The shared layout along with input state that never leaves this host
+#[derive(Debug)]
+pub struct Cluster {
+    layout: Layout,
+    /// Identifies the local screen, whatever its index or addresses
+    host_id: u64,
+    relative_hotkey: bool,
+    relative_grab: bool,
+    motion_remainder: (f64, f64),
+    entry: Entry,
+    locked: bool,
+    relative_key: u64,
+    /// A crossing that failed because the local input couldn't be grabbed
+    failed_crossing: Option<Focus>,
+}
+
+/// Where the cursor lands when focus moves to another screen
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize, Debug)]
+pub enum Entry {
+    /// Continue from the edge that was crossed
+    Edge,
+    /// Restore the last position on the screen
+    Restore,
+}
+
+impl FromStr for Entry {
+    type Err = ();
+
+    fn from_str(val: &str) -> Result<Self, ()> {
+        match val {
+            "edge" => Ok(Entry::Edge),
+            "restore" => Ok(Entry::Restore),
+            _ => Err(()),
+        }
+    }
 }
 
 #[derive(Clone, Copy, Serialize, Deserialize, Debug)]
@@ -20,74 +67,132 @@ pub struct Focus {
     pos: Dimensions,
 }
 
+impl Focus {
+    pub fn index(&self) -> Index {
+        self.index
+    }
+
+    pub fn pos(&self) -> (i32, i32) {
+        (self.pos.x, self.pos.y)
+    }
+}
+
 impl Cluster {
-    pub fn new(width: i32, height: i32, x: i32, y: i32) -> Self {
-        use std::fs::File;
-        use serde_json;
-
-        // This is probably weird place to load a file from
-        let app_dir = util::user_app_dir("elemeld").unwrap();
-        let screens = match File::open(app_dir.join("screens.json")) {
-            Ok(file) => serde_json::from_reader(file).map_err(|err| {
-                warn!("Failed to parse screens.json: {}", err);
-            }).ok(),
-            Err(err) => {
-                warn!("Failed to open screens.json {}", err);
-                None
-            },
-        };
-        
-        Cluster {
+    pub fn new<H>(host: &H, host_id: u64, rules: Rules, addrs: Vec<SocketAddr>) -> Self where
+        H: HostInterface
+    {
+        let (x, y) = host.cursor_pos();
+        let mut layout = Layout {
             local_screen: 0,
-            screens: match screens {
+            screens: match load_screens(host_id) {
                 Some(screens) => screens,
-                None => vec![Screen::new(width, height)],
+                None => vec![Screen::new(host_id, host.screen_size(), host.screen_size_mm(), addrs)],
             },
             focus: Focus {
                 index: 0,
                 pos: Dimensions { x: x, y: y },
             },
             selections: vec![0, 0],
+            removed: Vec::new(),
+            rules: rules,
+        };
+
+        // Without a match the first screen is the local one
+        if !layout.reset_local_screen(host_id) {
+            layout.screens[0].host_id = Some(host_id);
+        }
+
+        layout.focus.index = layout.local_screen;
+        Cluster {
+            layout: layout,
+            host_id: host_id,
+            relative_hotkey: false,
+            relative_grab: false,
+            motion_remainder: (0.0, 0.0),
+            entry: Entry::Edge,
+            locked: false,
+            relative_key: RELATIVE_TOGGLE_KEY,
+            failed_crossing: None,
         }
     }
     
-    pub fn process_host_event<H>(&mut self, host: &H, event: HostEvent) -> Option<NetEvent>
+    pub fn process_host_event<H>(&mut self, host: &H, event: HostEvent) -> Result<Option<NetEvent>, String>
         where H: HostInterface
     {
         match event {
             HostEvent::Motion(event) => {
+                // The local cursor moves natively, shared edges are detected
+                // with barriers instead, so only keep track of where it is
+                if self.locally_focused() {
+                    let (x, y) = host.cursor_pos();
+                    self.layout.focus.pos = Dimensions { x: x, y: y };
+                    return Ok(None);
+                }
+
+                let event = self.scale_motion(event);
+
+                // Forward raw deltas and stay on the focused screen
+                if self.is_relative() {
+                    return Ok(Some(NetEvent::Motion(event)));
+                }
+
                 if event.dx != 0 || event.dy != 0 {
                     let focus = Focus {
-                        index: self.focus.index,
+                        index: self.layout.focus.index,
                         pos: Dimensions {
-                            x: self.focus.pos.x + event.dx,
-                            y: self.focus.pos.y + event.dy,
+                            x: self.layout.focus.pos.x + event.dx,
+                            y: self.layout.focus.pos.y + event.dy,
                         }
                     };
                     
-                    self.refocus(host, focus);
-                    Some(NetEvent::Focus(focus))
-                } else { None }
+                    try!(self.move_focus(host, focus));
+                    Ok(Some(NetEvent::Focus(self.layout.focus)))
+                } else { Ok(None) }
+            },
+            HostEvent::Barrier(event) => {
+                if !self.locally_focused() {
+                    return Ok(None);
+                }
+
+                // Focus isn't tracked while the cursor moves natively
+                self.layout.focus.pos = Dimensions { x: event.x, y: event.y };
+
+                let focus = Focus {
+                    index: self.layout.focus.index,
+                    pos: Dimensions {
+                        x: event.x + event.dx,
+                        y: event.y + event.dy,
+                    }
+                };
+
+                try!(self.move_focus(host, focus));
+                Ok(Some(NetEvent::Focus(self.layout.focus)))
             },
             HostEvent::Selection(event) => {
                 println!("{:?}", event);
-                None
+                Ok(None)
             },
-            event => {
-                if !self.locally_focused() {
+            event => Ok(if !self.locally_focused() {
                     match event {
+                        HostEvent::Key(ref event) if event.key == self.relative_key => {
+                            if event.state {
+                                self.relative_hotkey = !self.relative_hotkey;
+                                info!("Relative pointer mode {}", if self.is_relative() { "on" } else { "off" });
+                            }
+                            None
+                        },
                         HostEvent::Button(event) => Some(NetEvent::Button(event)),
                         HostEvent::Key(event) => Some(NetEvent::Key(event)),
                         _ => None,
                     }
-                } else { None }
-            },
+                } else { None }),
         }
     }
 
     pub fn process_net_event(&mut self, event: NetEvent) -> Option<HostEvent> {
         if self.locally_focused() {
             match event {
+                NetEvent::Motion(event) => Some(HostEvent::Motion(event)),
                 NetEvent::Button(event) => Some(HostEvent::Button(event)),
                 NetEvent::Key(event) => Some(HostEvent::Key(event)),
                 _ => None,
@@ -95,55 +200,344 @@ impl Cluster {
         } else { None }
     }
 
-    pub fn focused_screen(&self) -> &Screen {
-        &self.screens[self.focus.index as usize]
+    /// Scale motion measured on the local screen so the pointer covers
+    /// the same physical distance on the focused screen, then apply the
+    /// focused screen's sensitivity and acceleration curve
+    fn scale_motion(&mut self, event: MotionEvent) -> MotionEvent {
+        let (dx, dy) = (event.dx as f64, event.dy as f64);
+        let factor = {
+            let local = &self.layout.screens[self.layout.local_screen as usize];
+            let focused = &self.layout.screens[self.layout.focus.index as usize];
+            let mut factor = match (local.density(), focused.density()) {
+                (Some(from), Some(to)) => to / from,
+                _ => 1.0,
+            };
+
+            factor *= focused.scale.unwrap_or(1.0);
+            if let Some(accel) = focused.accel {
+                let speed = (dx * dx + dy * dy).sqrt();
+                if speed > 0.0 {
+                    factor *= speed.powf(accel - 1.0);
+                }
+            }
+
+            factor
+        };
+
+        // Carry sub-pixel motion over to the next event
+        let (rem_x, rem_y) = self.motion_remainder;
+        let (dx, dy) = (dx * factor + rem_x, dy * factor + rem_y);
+        let (int_x, int_y) = (dx.trunc(), dy.trunc());
+        self.motion_remainder = (dx - int_x, dy - int_y);
+
+        MotionEvent { dx: int_x as i32, dy: int_y as i32 }
+    }
+
+    /// Relative mode is used while toggled by hotkey, or while
+    /// an application on the focused screen has grabbed the pointer
+    pub fn is_relative(&self) -> bool {
+        self.relative_hotkey || self.relative_grab
+    }
+
+    pub fn set_relative_grab(&mut self, grabbed: bool) {
+        if self.relative_grab != grabbed {
+            info!("Pointer {} on focused screen", if grabbed { "grabbed" } else { "released" });
+            self.relative_grab = grabbed;
+        }
+    }
+
+    /// Focus the local screen at the actual cursor position
+    pub fn local_focus(&self, x: i32, y: i32) -> Focus {
+        Focus {
+            index: self.layout.local_screen,
+            pos: Dimensions { x: x, y: y },
+        }
+    }
+
+    pub fn focused_screen_index(&self) -> Index {
+        self.layout.focus.index
+    }
+
+    pub fn focus(&self) -> Focus {
+        self.layout.focus
+    }
+
+    pub fn screen(&self, index: Index) -> Option<&Screen> {
+        self.layout.screens.get(index as usize)
+    }
+
+    /// Every screen other than the local screen
+    pub fn remote_screens(&self) -> Vec<&Screen> {
+        self.layout.screens.iter().enumerate()
+            .filter(|&(i, _)| i as Index != self.layout.local_screen)
+            .map(|(_, screen)| screen)
+            .collect()
+    }
+
+    /// Addresses of every screen other than the local screen
+    pub fn remote_addrs(&self) -> Vec<SocketAddr> {
+        self.remote_screens().into_iter()
+            .flat_map(|screen| screen.addrs())
+            .collect()
+    }
+
+    /// Check if any screen in the cluster is reachable through one of the addresses
+    pub fn contains_addr(&self, addrs: &[SocketAddr]) -> bool {
+        self.layout.screens.iter().any(|screen| {
+            screen.addrs.iter().any(|addr| addrs.iter().any(|other| addr.0.ip() == other.ip()))
+        })
+    }
+
+    /// The screen reachable through an address. Indices can differ between
+    /// hosts until their clusters converge, so peers are told apart this way.
+    pub fn find_addr(&self, addr: &SocketAddr) -> Option<Index> {
+        self.layout.screens.iter()
+            .position(|screen| screen.addrs.iter().any(|other| other.0.ip() == addr.ip()))
+            .map(|i| i as Index)
+    }
+
+    /// Check if the host's screen was removed from the cluster
+    pub fn is_removed(&self, host_id: u64) -> bool {
+        self.layout.is_removed(host_id)
+    }
+
+    pub fn removed_screens(&self) -> &[Removed] {
+        &self.layout.removed
+    }
+
+    /// Let a removed screen join the cluster again
+    pub fn readmit_screen(&mut self, host_id: u64) -> Result<(), String> {
+        match self.layout.removed.iter().position(|removed| removed.host_id == host_id) {
+            Some(i) => {
+                self.layout.removed.remove(i);
+                Ok(())
+            },
+            None => Err(format!("No removed screen {:016x}", host_id)),
+        }
     }
     
-    fn locally_focused(&self) -> bool {
-        self.focus.index == self.local_screen
+    pub fn host_id(&self) -> u64 {
+        self.host_id
     }
 
-    fn reset_local_screen(&mut self) {
-        for ip in util::get_host_ips().unwrap() {
-            for (i, screen) in self.screens.iter().enumerate() {
-                for addr in &screen.addrs {
-                    if addr.0.ip() == ip {
-                        self.local_screen = i as Index;
-                        return;
-                    }
+    /// The screen of the host with the given id
+    pub fn find_host(&self, host_id: u64) -> Option<Index> {
+        self.layout.screens.iter()
+            .position(|screen| screen.host_id == Some(host_id))
+            .map(|i| i as Index)
+    }
+
+    pub fn rules(&self) -> Rules {
+        self.layout.rules
+    }
+
+    /// Share new arbitration rules, returning whether they changed
+    pub fn set_rules(&mut self, rules: Rules) -> bool {
+        if self.layout.rules == rules {
+            return false;
+        }
+
+        self.layout.rules = rules;
+        true
+    }
+
+    pub fn local_index(&self) -> Index {
+        self.layout.local_screen
+    }
+
+    pub fn locally_focused(&self) -> bool {
+        self.layout.focus.index == self.layout.local_screen
+    }
+
+    /// Update the addresses advertised for the local screen,
+    /// returning whether they changed
+    pub fn set_local_addrs(&mut self, addrs: Vec<SocketAddr>) -> bool {
+        let addrs = addrs.into_iter().map(|addr| Addr(addr)).collect::<Vec<_>>();
+        let screen = &mut self.layout.screens[self.layout.local_screen as usize];
+        if screen.addrs == addrs {
+            return false;
+        }
+
+        screen.addrs = addrs;
+        true
+    }
+
+    pub fn set_entry(&mut self, entry: Entry) {
+        self.entry = entry;
+    }
+
+    pub fn set_relative_key(&mut self, key: u64) {
+        self.relative_key = key;
+    }
+
+    /// Move focus by local input, remembering where the cursor
+    /// left the previous screen and applying the entry mode
+    fn move_focus<H>(&mut self, host: &H, focus: Focus) -> Result<(), String> where
+        H: HostInterface
+    {
+        let mut focus = if self.locked {
+            // Stay on the current screen, pinned against the edge
+            let screen = &self.layout.screens[self.layout.focus.index as usize];
+            Focus { index: self.layout.focus.index, pos: screen.inside(focus.pos) }
+        } else {
+            self.normalize_focus(focus)
+        };
+
+        if focus.index != self.layout.focus.index {
+            self.layout.screens[self.layout.focus.index as usize].last_pos = Some(self.layout.focus.pos);
+            if self.entry == Entry::Restore {
+                let screen = &self.layout.screens[focus.index as usize];
+                if let Some(pos) = screen.last_pos {
+                    focus.pos = screen.inside(pos);
                 }
             }
         }
 
-        panic!("Local IP was not found in cluster");
+        let result = self.refocus(host, focus);
+        self.failed_crossing = if result.is_err() { Some(focus) } else { None };
+        result
     }
 
-    pub fn refocus<H>(&mut self, host: &H, focus: Focus) where
+    /// Try a crossing that failed to grab the local input again, as long as
+    /// the cursor is still held where it was, returning None if there's nothing
+    /// to retry
+    pub fn retry_crossing<H>(&mut self, host: &H) -> Option<Result<Focus, String>> where
+        H: HostInterface
+    {
+        let focus = match self.failed_crossing.take() {
+            Some(focus) => focus,
+            None => return None,
+        };
+
+        if !self.locally_focused() || host.cursor_pos() != self.layout.focus.pos() {
+            return None;
+        }
+
+        Some(self.move_focus(host, focus).map(|_| self.layout.focus))
+    }
+
+    /// Focus a screen without crossing an edge, at its last
+    /// position or at its centre if it has never been focused
+    pub fn focus_screen<H>(&mut self, host: &H, index: Index) -> Result<Focus, String> where
+        H: HostInterface
+    {
+        let pos = match self.layout.screens.get(index as usize) {
+            Some(screen) => screen.inside(screen.last_pos.unwrap_or(Dimensions {
+                x: screen.size.x / 2,
+                y: screen.size.y / 2,
+            })),
+            None => return Err(format!("No screen {}", index)),
+        };
+
+        if self.locally_focused() {
+            let (x, y) = host.cursor_pos();
+            self.layout.focus.pos = Dimensions { x: x, y: y };
+        }
+
+        if index != self.layout.focus.index {
+            self.layout.screens[self.layout.focus.index as usize].last_pos = Some(self.layout.focus.pos);
+        }
+
+        try!(self.refocus(host, Focus { index: index, pos: pos }));
+        Ok(self.layout.focus)
+    }
+
+    /// Find a screen by its name or index
+    pub fn find_screen(&self, name: &str) -> Option<Index> {
+        match self.layout.screens.iter().position(|screen| screen.name == name) {
+            Some(index) => Some(index as Index),
+            None => name.parse::<Index>().ok()
+                .and_then(|index| self.screen(index).map(|_| index)),
+        }
+    }
+
+    /// While locked, local input can't move focus off the focused screen
+    pub fn set_locked<H>(&mut self, host: &H, locked: bool) where
+        H: HostInterface
+    {
+        if self.locked != locked {
+            info!("Focus {}", if locked { "locked" } else { "unlocked" });
+            self.locked = locked;
+            self.update_barriers(host);
+        }
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.locked
+    }
+
+    /// Load the screen layout from screens.json again
+    pub fn reload_screens(&mut self) -> bool {
+        match load_screens(self.host_id) {
+            Some(screens) => self.set_screens(screens),
+            None => false,
+        }
+    }
+
+    /// Move focus, keeping it on the local screen if the
+    /// local pointer and keyboard can't be grabbed
+    pub fn refocus<H>(&mut self, host: &H, focus: Focus) -> Result<(), String> where
         H: HostInterface
     {
         let was_focused = self.locally_focused();
-        self.private_refocus(host, focus, was_focused);
+        self.private_refocus(host, focus, was_focused)
     }
     
-    fn private_refocus<H>(&mut self, host: &H, focus: Focus, was_focused: bool) where
+    fn private_refocus<H>(&mut self, host: &H, focus: Focus, was_focused: bool) -> Result<(), String> where
         H: HostInterface
     {
-        self.focus = self.normalize_focus(focus);
+        let prev_index = self.layout.focus.index;
+        self.layout.focus = self.normalize_focus(focus);
+        if self.layout.focus.index != prev_index {
+            self.relative_grab = false;
+        }
+
         if self.locally_focused() {
             if !was_focused {
                 host.ungrab_cursor();
                 host.ungrab_keyboard();
+                self.update_barriers(host);
             }
             
             host.send_event(HostEvent::Position(PositionEvent {
-                x: self.focus.pos.x, y: self.focus.pos.y,
+                x: self.layout.focus.pos.x, y: self.layout.focus.pos.y,
             }));
         } else {
             if was_focused {
-                host.grab_cursor();
-                host.grab_keyboard();
+                // Input would leak into local applications without the grabs
+                if let Err(err) = host.grab_cursor().and_then(|_| host.grab_keyboard()) {
+                    host.ungrab_cursor();
+                    host.ungrab_keyboard();
+                    let (x, y) = host.cursor_pos();
+                    self.layout.focus = self.local_focus(x, y);
+                    return Err(format!("Keeping focus on the local screen: {}", err));
+                }
+
+                self.update_barriers(host);
             }
         }
+
+        Ok(())
+    }
+
+    /// Place barriers on the local screen's edges that have neighbours
+    /// while it has focus, so crossings are detected without warping
+    pub fn update_barriers<H>(&self, host: &H) where
+        H: HostInterface
+    {
+        let barriers = if self.locally_focused() && !self.locked {
+            let screen = &self.layout.screens[self.layout.local_screen as usize];
+            Barriers {
+                top: screen.neighbour(Edge::Top).is_some(),
+                right: screen.neighbour(Edge::Right).is_some(),
+                bottom: screen.neighbour(Edge::Bottom).is_some(),
+                left: screen.neighbour(Edge::Left).is_some(),
+            }
+        } else {
+            Barriers::default()
+        };
+
+        host.set_barriers(barriers);
     }
 
     /// Walk through the screens untill the x and y are contained within a screen
@@ -153,11 +547,11 @@ impl Cluster {
     }
 
     fn normalize_x(&self, focus: Focus) -> Focus {
-        let screen = &self.screens[focus.index as usize];
+        let screen = &self.layout.screens[focus.index as usize];
         if focus.pos.x <= 0 {
-            match screen.edges.left {
+            match screen.neighbour(Edge::Left) {
                 Some(index) => {
-                    let new_screen = &self.screens[index as usize];
+                    let new_screen = &self.layout.screens[index as usize];
                     return self.normalize_x(Focus {
                         index: index,
                         pos: Dimensions {
@@ -177,9 +571,9 @@ impl Cluster {
                 },
             }
         } else if focus.pos.x >= screen.size.x - 1 {
-            match screen.edges.right {
+            match screen.neighbour(Edge::Right) {
                 Some(index) => {
-                    let new_screen = &self.screens[index as usize];
+                    let new_screen = &self.layout.screens[index as usize];
                     return self.normalize_x(Focus {
                         index: index,
                         pos: Dimensions {
@@ -204,11 +598,11 @@ impl Cluster {
     }
 
     fn normalize_y(&self, focus: Focus) -> Focus {
-        let screen = &self.screens[focus.index as usize];
+        let screen = &self.layout.screens[focus.index as usize];
         if focus.pos.y <= 0 {
-            match screen.edges.top {
+            match screen.neighbour(Edge::Top) {
                 Some(index) => {
-                    let new_screen = &self.screens[index as usize];
+                    let new_screen = &self.layout.screens[index as usize];
                     return self.normalize_y(Focus {
                         index: index,
                         pos: Dimensions {
@@ -228,9 +622,9 @@ impl Cluster {
                 },
             }
         } else if focus.pos.y >= screen.size.y - 1 {
-            match screen.edges.bottom {
+            match screen.neighbour(Edge::Bottom) {
                 Some(index) => {
-                    let new_screen = &self.screens[index as usize];
+                    let new_screen = &self.layout.screens[index as usize];
                     return self.normalize_y(Focus {
                         index: index,
                         pos: Dimensions {
@@ -256,11 +650,11 @@ impl Cluster {
 
     /// Add a new screen to the far right of the cluster
     fn add(&mut self, mut new_screen: Screen) {
-        let new_index = self.screens.len() as Index;
+        let new_index = self.layout.screens.len() as Index;
         let mut index = 0 as Index;
         
         loop {
-            let screen = &mut self.screens[index as usize];
+            let screen = &mut self.layout.screens[index as usize];
             index = match screen.edges.right {
                 Some(index) => index,
                 None => {
@@ -271,14 +665,30 @@ impl Cluster {
             }
         }
         
-        self.screens.push(new_screen);
+        self.layout.screens.push(new_screen);
     }
 
     /// Attempt to merge two clusters together
-    pub fn merge(&mut self, other: Self) {
+    pub fn merge(&mut self, other: Layout) {
+        for removed in other.removed {
+            if !self.layout.is_removed(removed.host_id) {
+                self.layout.removed.push(removed);
+            }
+        }
+
         'outer: for other_screen in other.screens {
+            if other_screen.host_id.map_or(false, |host_id| self.layout.is_removed(host_id)) {
+                continue;
+            }
+
+            if other_screen.host_id.is_some() &&
+                self.layout.screens.iter().any(|screen| screen.host_id == other_screen.host_id)
+            {
+                continue;
+            }
+
             for other_addr in &other_screen.addrs {
-                for screen in &self.screens {
+                for screen in &self.layout.screens {
                     for addr in &screen.addrs {
                         if addr.0.ip() == other_addr.0.ip() {
                             // TODO: Merge screens
@@ -293,57 +703,255 @@ impl Cluster {
         }
     }
 
-    /// Replace an existing cluster with a new cluster
-    pub fn replace<H>(&mut self, host: &H, mut other: Self) where
+    /// Replace the layout with one received from a peer, returning false
+    /// if the local screen is missing from it. Fails if the layout was
+    /// replaced but focus had to be kept on the local screen.
+    pub fn replace<H>(&mut self, host: &H, mut other: Layout) -> Result<bool, String> where
         H: HostInterface
     {
-        other.reset_local_screen();
-        
+        if !other.reset_local_screen(self.host_id) {
+            return Ok(false);
+        }
+
         let focus = other.focus;
         let was_focused = self.locally_focused();
-        other.private_refocus(host, focus, was_focused);
-        
-        *self = other;
+        self.layout = other;
+        try!(self.private_refocus(host, focus, was_focused));
+        Ok(true)
+    }
+
+    pub fn layout(&self) -> &Layout {
+        &self.layout
     }
 
     pub fn get_screens(&self) -> &[Screen] {
-        &self.screens
+        &self.layout.screens
     }
 
-    pub fn set_screens(&mut self, screens: Vec<Screen>) {
-        self.screens = screens;
-        self.reset_local_screen();
+    pub fn set_screens(&mut self, screens: Vec<Screen>) -> bool {
+        let prev = mem::replace(&mut self.layout.screens, screens);
+        if !self.layout.reset_local_screen(self.host_id) {
+            warn!("Local screen was not found in the layout");
+            self.layout.screens = prev;
+            return false;
+        }
+
+        true
+    }
+
+    pub fn rename_screen(&mut self, index: Index, name: String) -> Result<(), String> {
+        try!(self.check_index(index)).name = name;
+        Ok(())
+    }
+
+    /// Remove a screen from the layout, unlinking its neighbours. Its host
+    /// is remembered so the peer isn't merged back in until readmitted.
+    pub fn remove_screen<H>(&mut self, host: &H, index: Index) -> Result<(), String> where
+        H: HostInterface
+    {
+        try!(self.check_index(index));
+        if index == self.layout.local_screen {
+            return Err("Cannot remove the local screen".to_owned());
+        }
+
+        if index == self.layout.focus.index {
+            let local = self.layout.local_screen;
+            try!(self.focus_screen(host, local));
+        }
+
+        // Screens without a host id haven't been seen yet, so there's nothing to keep out
+        let removed = self.layout.screens.remove(index as usize);
+        if let Some(host_id) = removed.host_id {
+            self.layout.removed.push(Removed {
+                host_id: host_id,
+                name: removed.name,
+            });
+        }
+        let shift = |other: Index| if other > index { other - 1 } else { other };
+        self.layout.local_screen = shift(self.layout.local_screen);
+        self.layout.focus.index = shift(self.layout.focus.index);
+        for screen in &mut self.layout.screens {
+            for &edge in &EDGES {
+                let neighbour = screen.edges.get(edge);
+                *screen.edges.get_mut(edge) = match neighbour {
+                    Some(other) if other == index => None,
+                    Some(other) => Some(shift(other)),
+                    None => None,
+                };
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Link (or unlink) a screen's edge, along with the opposite edge of its neighbour
+    pub fn set_edge(&mut self, index: Index, edge: Edge, neighbour: Option<Index>) -> Result<(), String> {
+        try!(self.check_index(index));
+        if let Some(neighbour) = neighbour {
+            try!(self.check_index(neighbour));
+            if neighbour == index {
+                return Err("A screen cannot neighbour itself".to_owned());
+            }
+        }
+
+        // Unlink whatever was on either side before
+        let old = self.layout.screens[index as usize].edges.get(edge);
+        if let Some(old) = old {
+            let old_edges = &mut self.layout.screens[old as usize].edges;
+            if old_edges.get(edge.opposite()) == Some(index) {
+                *old_edges.get_mut(edge.opposite()) = None;
+            }
+        }
+
+        if let Some(neighbour) = neighbour {
+            let old = self.layout.screens[neighbour as usize].edges.get(edge.opposite());
+            if let Some(old) = old {
+                *self.layout.screens[old as usize].edges.get_mut(edge) = None;
+            }
+
+            *self.layout.screens[neighbour as usize].edges.get_mut(edge.opposite()) = Some(index);
+        }
+
+        *self.layout.screens[index as usize].edges.get_mut(edge) = neighbour;
+        Ok(())
+    }
+
+    pub fn set_edge_policy(&mut self, index: Index, edge: Edge, policy: EdgePolicy) -> Result<(), String> {
+        let screen = try!(self.check_index(index));
+        let mut policies = screen.policies.unwrap_or(Policies::default());
+        *policies.get_mut(edge) = policy;
+        screen.policies = Some(policies);
+        Ok(())
+    }
+
+    /// Set the pointer sensitivity and acceleration exponent used while
+    /// a screen is focused, leaving the ones that aren't given unchanged
+    pub fn set_pointer(&mut self, index: Index, scale: Option<f64>, accel: Option<f64>) -> Result<(), String> {
+        for &(name, value) in &[("scale", scale), ("acceleration", accel)] {
+            if let Some(value) = value {
+                if !(value.is_finite() && value > 0.0) {
+                    return Err(format!("Invalid {}: {}", name, value));
+                }
+            }
+        }
+
+        let screen = try!(self.check_index(index));
+        if scale.is_some() { screen.scale = scale }
+        if accel.is_some() { screen.accel = accel }
+        Ok(())
+    }
+
+    fn check_index(&mut self, index: Index) -> Result<&mut Screen, String> {
+        self.layout.screens.get_mut(index as usize).ok_or(format!("No screen {}", index))
+    }
+}
+
+impl Layout {
+    fn is_removed(&self, host_id: u64) -> bool {
+        self.removed.iter().any(|removed| removed.host_id == host_id)
+    }
+
+    /// The host id of the screen belonging to the host that sent the layout
+    pub fn local_host_id(&self) -> Option<u64> {
+        self.screens.get(self.local_screen as usize).and_then(|screen| screen.host_id)
+    }
+
+    /// Find the local screen by its host id,
+    /// returning false if it isn't in the cluster
+    fn reset_local_screen(&mut self, host_id: u64) -> bool {
+        match self.screens.iter().position(|screen| screen.host_id == Some(host_id)) {
+            Some(i) => {
+                self.local_screen = i as Index;
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// Link-local IPv6 addresses are only meaningful with the scope id of
+    /// the receiving host's interface, so rewrite the scope ids advertised
+    /// by the sender with the scope id the cluster was received on
+    pub fn localize_scopes(&mut self, from: &SocketAddr) {
+        let scope_id = match *from {
+            SocketAddr::V6(addr) if util::is_link_local_v6(addr.ip()) => addr.scope_id(),
+            _ => return,
+        };
+
+        for screen in &mut self.screens {
+            for addr in &mut screen.addrs {
+                if let SocketAddr::V6(ref mut addr) = addr.0 {
+                    if util::is_link_local_v6(addr.ip()) {
+                        *addr = SocketAddrV6::new(*addr.ip(), addr.port(), addr.flowinfo(), scope_id);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Screens in screens.json may not have host ids, so
+/// the local one can also be named after the host
+fn load_screens(host_id: u64) -> Option<Vec<Screen>> {
+    use std::fs::File;
+    use serde_json;
+
+    // This is probably weird place to load a file from
+    let app_dir = util::user_app_dir("elemeld").unwrap();
+    match File::open(app_dir.join("screens.json")) {
+        Ok(file) => serde_json::from_reader(file).map_err(|err| {
+            warn!("Failed to parse screens.json: {}", err);
+        }).ok().map(|mut screens: Vec<Screen>| {
+            if !screens.iter().any(|screen| screen.host_id == Some(host_id)) {
+                let name = util::get_host_name().unwrap();
+                let local = screens.iter_mut()
+                    .find(|screen| screen.host_id.is_none() && screen.name == name);
+                if let Some(screen) = local {
+                    screen.host_id = Some(host_id);
+                }
+            }
+
+            screens
+        }),
+        Err(err) => {
+            warn!("Failed to open screens.json {}", err);
+            None
+        },
     }
 }
 
 #[derive(Clone, Serialize, Deserialize, Debug)]
 pub struct Screen {
     name: String,
+    /// The persistent id of the screen's host
+    host_id: Option<u64>,
     size: Dimensions,
     edges: Edges,
     addrs: Vec<Addr>,
+    /// Physical size in millimetres, if known
+    size_mm: Option<Dimensions>,
+    /// Pointer sensitivity multiplier
+    scale: Option<f64>,
+    /// Acceleration curve exponent (1.0 is linear)
+    accel: Option<f64>,
+    /// Where the cursor was when the screen last lost focus
+    last_pos: Option<Dimensions>,
+    policies: Option<Policies>,
 }
 
 impl Screen {
-    pub fn new(width: i32, height: i32) -> Self {
-        let port = 24242; // FIXME: Get from config
+    pub fn new(host_id: u64, size: (i32, i32), size_mm: (i32, i32), addrs: Vec<SocketAddr>) -> Self {
         Screen {
             name: util::get_host_name().unwrap(),
-            addrs: util::get_host_ips().unwrap().into_iter()
-                .filter_map(|addr| match addr {
-                    IpAddr::V4(addr) =>
-                        if !addr.is_loopback() {
-                            Some(SocketAddr::V4(SocketAddrV4::new(addr, port)))
-                        } else { None },
-                    IpAddr::V6(addr) =>
-                        if !addr.is_loopback() {
-                            Some(SocketAddr::V6(SocketAddrV6::new(addr, port, 0, 0)))
-                        } else { None },
-                })
-                .map(|addr| Addr(addr))
-                .collect::<Vec<_>>(),
-            
-            size: Dimensions { x: width, y: height },
+            host_id: Some(host_id),
+            addrs: addrs.into_iter().map(|addr| Addr(addr)).collect(),
+            size: Dimensions { x: size.0, y: size.1 },
+            size_mm: if size_mm.0 > 0 && size_mm.1 > 0 {
+                Some(Dimensions { x: size_mm.0, y: size_mm.1 })
+            } else { None },
+            scale: None,
+            accel: None,
+            last_pos: None,
+            policies: None,
             edges: Edges {
                 top: None,
                 right: None,
@@ -353,19 +961,109 @@ impl Screen {
         }
     }
 
-    pub fn default_route(&self) -> &SocketAddr {
-        &self.addrs[0].0
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn host_id(&self) -> Option<u64> {
+        self.host_id
     }
+
+    pub fn addrs(&self) -> Vec<SocketAddr> {
+        self.addrs.iter().map(|addr| addr.0).collect()
+    }
+
+    pub fn size(&self) -> (i32, i32) {
+        (self.size.x, self.size.y)
+    }
+
+    pub fn edge(&self, edge: Edge) -> Option<Index> {
+        self.edges.get(edge)
+    }
+
+    /// Pointer sensitivity and acceleration exponent
+    pub fn pointer(&self) -> (f64, f64) {
+        (self.scale.unwrap_or(1.0), self.accel.unwrap_or(1.0))
+    }
+
+    pub fn policy(&self, edge: Edge) -> EdgePolicy {
+        self.policies.map_or(EdgePolicy::Cross, |policies| policies.get(edge))
+    }
+
+    /// The screen focus crosses over to through an edge
+    fn neighbour(&self, edge: Edge) -> Option<Index> {
+        match self.policy(edge) {
+            EdgePolicy::Cross => self.edges.get(edge),
+            EdgePolicy::Block => None,
+        }
+    }
+
+    /// Keep a position off the edges so it doesn't cross into a neighbour
+    fn inside(&self, pos: Dimensions) -> Dimensions {
+        Dimensions {
+            x: cmp::max(1, cmp::min(pos.x, self.size.x - 2)),
+            y: cmp::max(1, cmp::min(pos.y, self.size.y - 2)),
+        }
+    }
+
+    /// Pixels per millimetre
+    fn density(&self) -> Option<f64> {
+        self.size_mm.map(|size_mm| {
+            let x = self.size.x as f64 / size_mm.x as f64;
+            let y = self.size.y as f64 / size_mm.y as f64;
+            (x + y) / 2.0
+        })
+    }
+}
+
+/// A screen removed from the cluster, named so it can be found to readmit
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct Removed {
+    pub host_id: u64,
+    pub name: String,
 }
 
 #[derive(Clone, PartialEq, Eq, Hash, Debug)]
 struct Addr(SocketAddr);
 
+/// Formats IPv6 addresses with their scope id (eg. `[fe80::1%2]:24242`)
+impl fmt::Display for Addr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.0 {
+            SocketAddr::V6(addr) if addr.scope_id() != 0 =>
+                write!(f, "[{}%{}]:{}", addr.ip(), addr.scope_id(), addr.port()),
+            addr => write!(f, "{}", addr),
+        }
+    }
+}
+
+impl FromStr for Addr {
+    type Err = ();
+
+    fn from_str(val: &str) -> Result<Self, ()> {
+        // std's parser doesn't understand scope ids, so strip it out first
+        if let (Some(start), Some(end)) = (val.find('%'), val.find(']')) {
+            if start < end {
+                let scope_id = try!(val[start + 1..end].parse::<u32>().map_err(|_| ()));
+                let addr = format!("{}{}", &val[..start], &val[end..]);
+                return match addr.parse::<SocketAddr>() {
+                    Ok(SocketAddr::V6(addr)) => Ok(Addr(SocketAddr::V6(SocketAddrV6::new(
+                        *addr.ip(), addr.port(), addr.flowinfo(), scope_id
+                    )))),
+                    _ => Err(()),
+                };
+            }
+        }
+
+        val.parse::<SocketAddr>().map(|addr| Addr(addr)).map_err(|_| ())
+    }
+}
+
 impl serde::Serialize for Addr {
     fn serialize<S>(&self, serializer: &mut S) -> Result<(), S::Error>
         where S: serde::Serializer
     {
-        serializer.serialize_str(&format!("{}", self.0))
+        serializer.serialize_str(&format!("{}", self))
     }
 }
 
@@ -380,10 +1078,9 @@ impl serde::Deserialize for Addr {
             fn visit_str<E>(&mut self, val: &str) -> Result<Self::Value, E>
                 where E: serde::de::Error,
             {
-                match val.parse::<SocketAddr>() {
-                    Ok(addr) => Ok(Addr(addr)),
-                    Err(_) => Err(serde::de::Error::custom("expected socket address")),
-                }
+                val.parse::<Addr>().map_err(|_| {
+                    serde::de::Error::custom("expected socket address")
+                })
             }
         }
 
@@ -404,3 +1101,126 @@ struct Edges {
     bottom: Option<Index>,
     left: Option<Index>,
 }
+
+impl Edges {
+    fn get(&self, edge: Edge) -> Option<Index> {
+        match edge {
+            Edge::Top => self.top,
+            Edge::Right => self.right,
+            Edge::Bottom => self.bottom,
+            Edge::Left => self.left,
+        }
+    }
+
+    fn get_mut(&mut self, edge: Edge) -> &mut Option<Index> {
+        match edge {
+            Edge::Top => &mut self.top,
+            Edge::Right => &mut self.right,
+            Edge::Bottom => &mut self.bottom,
+            Edge::Left => &mut self.left,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Edge {
+    Top,
+    Right,
+    Bottom,
+    Left,
+}
+
+pub const EDGES: [Edge; 4] = [Edge::Top, Edge::Right, Edge::Bottom, Edge::Left];
+
+impl Edge {
+    pub fn opposite(&self) -> Edge {
+        match *self {
+            Edge::Top => Edge::Bottom,
+            Edge::Right => Edge::Left,
+            Edge::Bottom => Edge::Top,
+            Edge::Left => Edge::Right,
+        }
+    }
+}
+
+impl FromStr for Edge {
+    type Err = ();
+
+    fn from_str(val: &str) -> Result<Self, ()> {
+        match val {
+            "top" => Ok(Edge::Top),
+            "right" => Ok(Edge::Right),
+            "bottom" => Ok(Edge::Bottom),
+            "left" => Ok(Edge::Left),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Whether focus may cross an edge into its neighbour
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize, Debug)]
+pub enum EdgePolicy {
+    Cross,
+    /// Keep the neighbour in the layout, but never cross into it
+    Block,
+}
+
+impl FromStr for EdgePolicy {
+    type Err = ();
+
+    fn from_str(val: &str) -> Result<Self, ()> {
+        match val {
+            "cross" => Ok(EdgePolicy::Cross),
+            "block" => Ok(EdgePolicy::Block),
+            _ => Err(()),
+        }
+    }
+}
+
+impl fmt::Display for EdgePolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            EdgePolicy::Cross => "cross",
+            EdgePolicy::Block => "block",
+        })
+    }
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize, Debug)]
+struct Policies {
+    top: EdgePolicy,
+    right: EdgePolicy,
+    bottom: EdgePolicy,
+    left: EdgePolicy,
+}
+
+impl Default for Policies {
+    fn default() -> Self {
+        Policies {
+            top: EdgePolicy::Cross,
+            right: EdgePolicy::Cross,
+            bottom: EdgePolicy::Cross,
+            left: EdgePolicy::Cross,
+        }
+    }
+}
+
+impl Policies {
+    fn get(&self, edge: Edge) -> EdgePolicy {
+        match edge {
+            Edge::Top => self.top,
+            Edge::Right => self.right,
+            Edge::Bottom => self.bottom,
+            Edge::Left => self.left,
+        }
+    }
+
+    fn get_mut(&mut self, edge: Edge) -> &mut EdgePolicy {
+        match edge {
+            Edge::Top => &mut self.top,
+            Edge::Right => &mut self.right,
+            Edge::Bottom => &mut self.bottom,
+            Edge::Left => &mut self.left,
+        }
+    }
+}
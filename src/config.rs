@@ -0,0 +1,268 @@
+use util;
+use cluster::{self, Entry};
+use io::ESCAPE_KEYS;
+use arbiter::{Policy, Rules};
+
+use serde_json::{self, Value};
+
+use std::{io, fs};
+use std::path::PathBuf;
+use std::net::{IpAddr, SocketAddr};
+
+/// Every runtime option, loaded from `config.json` in the app dir.
+/// Missing keys fall back to the defaults.
+#[derive(Clone, PartialEq, Serialize, Deserialize, Debug)]
+pub struct Config {
+    pub network: Network,
+    /// Peer discovery, multicast or mdns
+    pub discovery: String,
+    pub config_server: Server,
+    pub hotkeys: Hotkeys,
+    pub pointer: Pointer,
+    pub reclaim: Reclaim,
+    pub arbitration: Arbitration,
+}
+
+#[derive(Clone, PartialEq, Serialize, Deserialize, Debug)]
+pub struct Network {
+    /// :: for dual-stack IPv4/IPv6
+    pub bind_addr: String,
+    pub multicast_addrs: Vec<String>,
+    pub port: u16,
+    /// Interfaces to use, * matches any suffix
+    pub interfaces: Vec<String>,
+    pub ignore_interfaces: Vec<String>,
+}
+
+/// The web UI's config server
+#[derive(Clone, PartialEq, Serialize, Deserialize, Debug)]
+pub struct Server {
+    pub addr: String,
+    /// Require the session token, needed to listen on non-loopback addresses
+    pub auth: bool,
+}
+
+#[derive(Clone, PartialEq, Serialize, Deserialize, Debug)]
+pub struct Hotkeys {
+    /// Keysym toggling relative pointer mode
+    pub relative_toggle: u64,
+    /// Keysyms that force focus back to the local screen when
+    /// held together, or none to disable
+    pub escape: Vec<u64>,
+}
+
+#[derive(Clone, PartialEq, Serialize, Deserialize, Debug)]
+pub struct Pointer {
+    pub accelerated: bool,
+    /// Cursor entry on a screen, edge or restore
+    pub entry: String,
+    /// Maximum focus updates sent per second
+    pub focus_rate: u32,
+}
+
+/// Taking focus back by using a host's own keyboard or mouse
+#[derive(Clone, PartialEq, Serialize, Deserialize, Debug)]
+pub struct Reclaim {
+    pub enabled: bool,
+    /// Time since a peer last used the screen before local input can take it back
+    pub grace_ms: u64,
+}
+
+/// Choosing which host controls focus when several are used at once. The
+/// policy and idle time are shared with the cluster so every host judges
+/// claims the same way. The last host to start or reload its config sets
+/// them, and hosts whose config differs log a warning.
+#[derive(Clone, PartialEq, Serialize, Deserialize, Debug)]
+pub struct Arbitration {
+    /// first-wins, priority or takeover
+    pub policy: String,
+    /// Compared against other hosts' priorities by the priority policy
+    pub priority: i32,
+    /// Time without input before the controlling host gives up control
+    pub idle_ms: u64,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            network: Network {
+                bind_addr: "::".to_owned(),
+                multicast_addrs: vec!["224.0.2.42".to_owned(), "ff02::242:42".to_owned()],
+                port: 24242,
+                interfaces: vec![],
+                ignore_interfaces: ["docker*", "br-*", "veth*", "virbr*", "vnet*", "tun*", "tap*", "wg*"]
+                    .iter().map(|name| (*name).to_owned()).collect(),
+            },
+            discovery: "multicast".to_owned(),
+            config_server: Server {
+                addr: "127.0.0.1:3012".to_owned(),
+                auth: true,
+            },
+            hotkeys: Hotkeys {
+                relative_toggle: cluster::RELATIVE_TOGGLE_KEY,
+                escape: ESCAPE_KEYS.to_vec(),
+            },
+            pointer: Pointer {
+                accelerated: true,
+                entry: "edge".to_owned(),
+                focus_rate: 125,
+            },
+            reclaim: Reclaim {
+                enabled: false,
+                grace_ms: 2000,
+            },
+            arbitration: Arbitration {
+                policy: "takeover".to_owned(),
+                priority: 0,
+                idle_ms: 2000,
+            },
+        }
+    }
+}
+
+impl Config {
+    pub fn entry(&self) -> Entry {
+        self.pointer.entry.parse().unwrap_or(Entry::Edge)
+    }
+
+    pub fn rules(&self) -> Rules {
+        Rules {
+            policy: self.arbitration.policy.parse().unwrap_or(Policy::Takeover),
+            idle_ms: self.arbitration.idle_ms,
+        }
+    }
+
+    /// Check the options that can't be checked by their type
+    fn validate(&self) -> io::Result<()> {
+        let invalid = |msg: String| Err(io::Error::new(io::ErrorKind::InvalidData, msg));
+
+        match &self.discovery[..] {
+            "multicast" | "mdns" => (),
+            discovery => return invalid(format!("Unknown discovery mechanism: {}", discovery)),
+        }
+
+        match self.config_server.addr.parse::<SocketAddr>() {
+            Ok(addr) => if !self.config_server.auth && !is_loopback(&addr.ip()) {
+                return invalid(format!("The config server can only listen on {} with auth enabled", addr));
+            },
+            Err(err) => return invalid(format!("Invalid config server address {}: {}", self.config_server.addr, err)),
+        }
+
+        if self.pointer.entry.parse::<Entry>().is_err() {
+            return invalid(format!("Unknown entry mode: {}", self.pointer.entry));
+        }
+
+        if self.arbitration.policy.parse::<Policy>().is_err() {
+            return invalid(format!("Unknown arbitration policy: {}", self.arbitration.policy));
+        }
+
+        Ok(())
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).unwrap()
+    }
+}
+
+/// Options given on the command line, which take precedence over the file
+#[derive(Clone, Default, Debug)]
+pub struct Overrides {
+    pub bind_addr: Option<String>,
+    pub multicast_addrs: Option<Vec<String>>,
+    pub port: Option<u16>,
+    pub interfaces: Option<Vec<String>>,
+    pub ignore_interfaces: Option<Vec<String>>,
+    pub discovery: Option<String>,
+    pub focus_rate: Option<u32>,
+    pub entry: Option<String>,
+    pub accelerated: Option<bool>,
+}
+
+/// Where the config comes from, kept around so it can be reloaded
+#[derive(Clone, Debug)]
+pub struct Source {
+    pub path: PathBuf,
+    pub overrides: Overrides,
+}
+
+impl Source {
+    /// The default path is only looked up when no path is given
+    pub fn new(path: Option<PathBuf>, overrides: Overrides) -> io::Result<Self> {
+        let path = match path {
+            Some(path) => path,
+            None => try!(default_path()),
+        };
+
+        Ok(Source {
+            path: path,
+            overrides: overrides,
+        })
+    }
+
+    pub fn load(&self) -> io::Result<Config> {
+        let mut value = serde_json::to_value(&Config::default());
+        match fs::File::open(&self.path) {
+            Ok(file) => {
+                let file = try!(serde_json::from_reader(file).map_err(|err| io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Failed to parse {}: {}", self.path.display(), err)
+                )));
+
+                merge(&mut value, file);
+            },
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => (),
+            Err(err) => return Err(err),
+        }
+
+        let mut config: Config = try!(serde_json::from_value(value).map_err(|err| io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Invalid config in {}: {}", self.path.display(), err)
+        )));
+
+        self.apply(&mut config);
+        try!(config.validate());
+        Ok(config)
+    }
+
+    fn apply(&self, config: &mut Config) {
+        let overrides = self.overrides.clone();
+        if let Some(addr) = overrides.bind_addr { config.network.bind_addr = addr }
+        if let Some(addrs) = overrides.multicast_addrs { config.network.multicast_addrs = addrs }
+        if let Some(port) = overrides.port { config.network.port = port }
+        if let Some(names) = overrides.interfaces { config.network.interfaces = names }
+        if let Some(names) = overrides.ignore_interfaces { config.network.ignore_interfaces = names }
+        if let Some(discovery) = overrides.discovery { config.discovery = discovery }
+        if let Some(rate) = overrides.focus_rate { config.pointer.focus_rate = rate }
+        if let Some(entry) = overrides.entry { config.pointer.entry = entry }
+        if let Some(accelerated) = overrides.accelerated { config.pointer.accelerated = accelerated }
+    }
+}
+
+pub fn default_path() -> io::Result<PathBuf> {
+    util::user_app_dir("elemeld")
+        .map(|dir| dir.join("config.json"))
+        .ok_or(io::Error::new(io::ErrorKind::NotFound, "No home directory"))
+}
+
+pub fn is_loopback(ip: &IpAddr) -> bool {
+    match *ip {
+        IpAddr::V4(ip) => ip.is_loopback(),
+        IpAddr::V6(ip) => ip.is_loopback(),
+    }
+}
+
+/// Recursively overwrite the keys in `base` with the keys in `other`
+fn merge(base: &mut Value, other: Value) {
+    match (base, other) {
+        (&mut Value::Object(ref mut base), Value::Object(other)) => {
+            for (key, val) in other {
+                if base.contains_key(&key) {
+                    merge(base.get_mut(&key).unwrap(), val);
+                } else {
+                    warn!("Unknown config option: {}", key);
+                }
+            }
+        },
+        (base, other) => *base = other,
+    }
+}
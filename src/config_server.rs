@@ -1,30 +1,177 @@
-use io::*;
+use api::{self, Hello, Reply, API_VERSION};
+use util;
 
 use mio;
 use ws::*;
 use serde_json;
 
+use std::{io, str};
+use std::sync::Arc;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+
+/// Web UI assets embedded at build time: path, content type and body
+const ASSETS: &'static [(&'static str, &'static str, &'static str)] = &[
+    ("/", "text/html; charset=utf-8", include_str!("../web-ui/index.html")),
+    ("/index.html", "text/html; charset=utf-8", include_str!("../web-ui/index.html")),
+    ("/css/core.css", "text/css; charset=utf-8", include_str!("../web-ui/css/core.css")),
+    ("/js/core.js", "application/javascript; charset=utf-8", include_str!("../web-ui/js/core.js")),
+    ("/js/element.js", "application/javascript; charset=utf-8", include_str!("../web-ui/js/element.js")),
+];
+
+/// Who may use the config server
+pub struct Access {
+    /// Required as the `token` query parameter of the websocket handshake
+    pub token: Option<String>,
+    /// Reject requests addressed to anything but a loopback host,
+    /// so other sites can't reach the server through DNS rebinding
+    pub loopback_only: bool,
+}
+
+/// Forwarded to the hub along with the client to reply to
+pub enum ClientEvent {
+    Connected,
+    Request(api::Request),
+}
+
 pub struct ConfigServer {
     client: Sender,
-    server: mio::Sender<(NetEvent, Sender)>,
+    server: mio::Sender<(ClientEvent, Sender)>,
+    access: Arc<Access>,
 }
 
 impl ConfigServer {
-    pub fn new(client: Sender, server: mio::Sender<(NetEvent, Sender)>) -> Self {
-        ConfigServer { client: client, server: server }
+    pub fn new(client: Sender, server: mio::Sender<(ClientEvent, Sender)>, access: Arc<Access>) -> Self {
+        ConfigServer { client: client, server: server, access: access }
+    }
+
+    fn send(&self, msg: &api::Message) -> Result<()> {
+        self.client.send(serde_json::to_string(msg).unwrap())
+    }
+
+    /// Only accept the websocket from pages served by this server
+    /// and from clients that know the session token
+    fn authorize(&self, req: &Request, host: &str) -> Result<bool> {
+        if let Some(origin) = try!(req.origin()) {
+            if origin != format!("http://{}", host) {
+                warn!("Rejected config client from origin {}", origin);
+                return Ok(false);
+            }
+        }
+
+        if let Some(ref token) = self.access.token {
+            let valid = query_param(req.resource(), "token")
+                .map_or(false, |given| constant_time_eq(given.as_bytes(), token.as_bytes()));
+
+            if !valid {
+                warn!("Rejected config client with an invalid token");
+                return Ok(false);
+            }
+        }
+
+        Ok(true)
     }
 }
 
 impl Handler for ConfigServer {
+    /// Serve the web UI over plain HTTP on the same port as the websocket
+    fn on_request(&mut self, req: &Request) -> Result<Response> {
+        let host = match req.header("host").and_then(|host| str::from_utf8(host).ok()) {
+            Some(host) => host.to_owned(),
+            None => return Ok(forbidden()),
+        };
+
+        if self.access.loopback_only && !is_loopback_host(&host) {
+            warn!("Rejected config request for host {}", host);
+            return Ok(forbidden());
+        }
+
+        if req.header("upgrade").is_some() {
+            return if try!(self.authorize(req, &host)) {
+                Response::from_request(req)
+            } else {
+                Ok(forbidden())
+            };
+        }
+
+        let path = req.resource().split('?').next().unwrap_or("/");
+        match ASSETS.iter().find(|&&(asset, _, _)| asset == path) {
+            Some(&(_, content_type, body)) => {
+                let mut res = Response::new(200, "OK", body.as_bytes().to_vec());
+                res.headers_mut().push(("Content-Type".to_owned(), content_type.as_bytes().to_vec()));
+                Ok(res)
+            },
+            None => Ok(Response::new(404, "Not Found", b"404 - Not Found".to_vec())),
+        }
+    }
+
     fn on_open(&mut self, _: Handshake) -> Result<()> {
-        self.server.send((NetEvent::RequestCluster, self.client.clone())).unwrap();
+        try!(self.send(&api::Message::Hello(Hello { version: API_VERSION })));
+        self.server.send((ClientEvent::Connected, self.client.clone())).unwrap();
         Ok(())
     }
 
     fn on_message(&mut self, msg: Message) -> Result<()> {
-        let msg = msg.as_text().unwrap();
-        let event = serde_json::from_str(msg).unwrap();
-        self.server.send((event, self.client.clone())).unwrap();
-        Ok(())
+        match serde_json::from_str::<api::Request>(try!(msg.as_text())) {
+            Ok(request) => {
+                self.server.send((ClientEvent::Request(request), self.client.clone())).unwrap();
+                Ok(())
+            },
+            Err(err) => self.send(&api::Message::Response {
+                id: None,
+                result: Reply::Error(format!("Invalid request: {}", err)),
+            }),
+        }
+    }
+}
+
+pub fn token_path() -> PathBuf {
+    util::user_runtime_dir("elemeld").join("token")
+}
+
+/// Generate a new session token and write it where only the user can read it
+pub fn create_token(path: &Path) -> io::Result<String> {
+    let mut buf = [0u8; 16];
+    try!(util::random_bytes(&mut buf));
+    let token = buf.iter().map(|byte| format!("{:02x}", byte)).collect::<String>();
+    try!(util::write_private(path, token.as_bytes()));
+    Ok(token)
+}
+
+fn forbidden() -> Response {
+    Response::new(403, "Forbidden", b"403 - Forbidden".to_vec())
+}
+
+fn query_param<'a>(resource: &'a str, name: &str) -> Option<&'a str> {
+    resource.splitn(2, '?').nth(1).and_then(|query| {
+        query.split('&')
+            .filter_map(|pair| {
+                let mut parts = pair.splitn(2, '=');
+                match (parts.next(), parts.next()) {
+                    (Some(key), Some(val)) if key == name => Some(val),
+                    _ => None,
+                }
+            })
+            .next()
+    })
+}
+
+/// Check a host header (eg. `localhost:3012` or `[::1]:3012`) names a loopback address
+fn is_loopback_host(host: &str) -> bool {
+    let name = if host.starts_with('[') {
+        host[1..].split(']').next().unwrap_or("")
+    } else {
+        host.split(':').next().unwrap_or("")
+    };
+
+    name == "localhost" || match name.parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) => ip.is_loopback(),
+        Ok(IpAddr::V6(ip)) => ip.is_loopback(),
+        Err(_) => false,
     }
 }
+
+/// Compare without leaking how much of the token matched
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
@@ -0,0 +1,212 @@
+use util;
+
+use mio::*;
+use serde_json;
+
+use libc;
+
+use std::{io, fs};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::{UnixListener, UnixStream};
+
+/// How long a client has to send its request
+pub const REQUEST_TIMEOUT_MS: u64 = 1000;
+const MAX_REQUEST_SIZE: usize = 4096;
+
+#[derive(Serialize, Deserialize, Debug)]
+pub enum Request {
+    Status,
+    Screens,
+    /// Focus a screen by name or index
+    Focus(String),
+    /// Set a screen's pointer sensitivity and acceleration exponent
+    Pointer { screen: String, scale: Option<f64>, accel: Option<f64> },
+    /// Keep focus on the current screen
+    Lock,
+    Unlock,
+    Reload,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub enum Response {
+    Ok,
+    Status(Status),
+    Screens(Vec<ScreenInfo>),
+    Error(String),
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Status {
+    pub state: String,
+    pub screen: String,
+    pub focus: String,
+    pub locked: bool,
+    pub relative: bool,
+    pub peers: usize,
+    pub peers_online: usize,
+    /// Address of the web UI, including the session token
+    pub ui: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ScreenInfo {
+    pub index: u8,
+    pub name: String,
+    pub addrs: Vec<String>,
+    pub local: bool,
+    pub focused: bool,
+}
+
+pub fn socket_path() -> PathBuf {
+    util::user_runtime_dir("elemeld").join("control.sock")
+}
+
+/// Unix domain socket used by `elemeld ctl` to control a running daemon.
+/// Each connection carries a single JSON request and response line.
+pub struct ControlServer {
+    listener: UnixListener,
+    path: PathBuf,
+}
+
+impl ControlServer {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        if let Some(dir) = path.parent() {
+            try!(util::create_private_dir(dir));
+        }
+
+        // Only one daemon can run per user, so an existing socket is stale
+        if UnixStream::connect(path).is_ok() {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrInUse,
+                format!("elemeld is already running ({})", path.display())
+            ));
+        }
+
+        let _ = fs::remove_file(path);
+
+        // Only the owner may control the daemon, so the socket
+        // must never exist with looser permissions
+        let umask = unsafe { libc::umask(0o177) };
+        let listener = UnixListener::bind(path);
+        unsafe { libc::umask(umask) };
+
+        let listener = try!(listener);
+        try!(listener.set_nonblocking(true));
+
+        info!("Listening for control requests on {}", path.display());
+        Ok(ControlServer { listener: listener, path: path.to_owned() })
+    }
+
+    pub fn accept(&self) -> io::Result<Option<ControlClient>> {
+        match self.listener.accept() {
+            Ok((stream, _)) => {
+                try!(stream.set_nonblocking(true));
+                Ok(Some(ControlClient { stream: stream, buf: Vec::new() }))
+            },
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+}
+
+impl Drop for ControlServer {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// A control connection, read without blocking until its request line arrives
+pub struct ControlClient {
+    stream: UnixStream,
+    buf: Vec<u8>,
+}
+
+impl ControlClient {
+    /// Read whatever has arrived, returning the request once it's complete
+    pub fn read(&mut self) -> io::Result<Option<Request>> {
+        let mut chunk = [0u8; 1024];
+        loop {
+            match self.stream.read(&mut chunk) {
+                Ok(0) => return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "Connection closed before a request was sent"
+                )),
+                Ok(len) => self.buf.extend_from_slice(&chunk[..len]),
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => return Ok(None),
+                Err(err) => return Err(err),
+            }
+
+            if let Some(end) = self.buf.iter().position(|&byte| byte == b'\n') {
+                return serde_json::from_reader(&self.buf[..end]).map(Some).map_err(|err| io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Invalid control request: {}", err)
+                ));
+            }
+
+            if self.buf.len() > MAX_REQUEST_SIZE {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "Control request too long"));
+            }
+        }
+    }
+
+    /// Responses are small enough to fit in the socket buffer, so
+    /// this only fails if the client stopped reading
+    pub fn respond(&self, response: &Response) -> io::Result<()> {
+        let mut msg = serde_json::to_string(response).unwrap();
+        msg.push('\n');
+        (&self.stream).write_all(msg.as_bytes())
+    }
+}
+
+/// Send a request to the running daemon and wait for its response
+pub fn request(path: &Path, request: &Request) -> io::Result<Response> {
+    let mut stream = try!(UnixStream::connect(path));
+    let msg = serde_json::to_string(request).unwrap();
+    try!(stream.write_all(msg.as_bytes()));
+    try!(stream.write_all(b"\n"));
+
+    let mut msg = String::new();
+    try!(stream.read_to_string(&mut msg));
+    serde_json::from_str(&msg).map_err(|err| io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Invalid control response: {}", err)
+    ))
+}
+
+/*
+ * FIXME(Future):
+ * Method delegation: https://github.com/rust-lang/rfcs/pull/1406
+ */
+impl Evented for ControlServer {
+    fn register(&self, selector: &mut Selector, token: Token, interest: EventSet, opts: PollOpt) -> io::Result<()> {
+        selector.register(self.listener.as_raw_fd(), token, interest, opts)
+    }
+
+    fn reregister(&self, selector: &mut Selector, token: Token, interest: EventSet, opts: PollOpt) -> io::Result<()> {
+        selector.reregister(self.listener.as_raw_fd(), token, interest, opts)
+    }
+
+    fn deregister(&self, selector: &mut Selector) -> io::Result<()> {
+        selector.deregister(self.listener.as_raw_fd())
+    }
+}
+
+/*
+ * FIXME(Future):
+ * Method delegation: https://github.com/rust-lang/rfcs/pull/1406
+ */
+impl Evented for ControlClient {
+    fn register(&self, selector: &mut Selector, token: Token, interest: EventSet, opts: PollOpt) -> io::Result<()> {
+        selector.register(self.stream.as_raw_fd(), token, interest, opts)
+    }
+
+    fn reregister(&self, selector: &mut Selector, token: Token, interest: EventSet, opts: PollOpt) -> io::Result<()> {
+        selector.reregister(self.stream.as_raw_fd(), token, interest, opts)
+    }
+
+    fn deregister(&self, selector: &mut Selector) -> io::Result<()> {
+        selector.deregister(self.stream.as_raw_fd())
+    }
+}
@@ -0,0 +1,222 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+/// Largest payload carried by a single datagram, chosen so a datagram
+/// fits in the IPv6 minimum MTU (1280) without IP fragmentation
+pub const FRAGMENT_SIZE: usize = 1200;
+
+/// Largest message that can be split into fragments
+pub const MAX_MESSAGE_SIZE: usize = 64 * 1024;
+
+/// Largest datagram that will ever be received
+pub const MAX_PACKET_SIZE: usize = FRAGMENT_SIZE + FRAGMENT_HEADER;
+
+const COMPLETE: u8 = 0;
+const FRAGMENT: u8 = 1;
+const FRAGMENT_HEADER: usize = 9;
+
+/// How long to wait for the rest of a message
+const REASSEMBLY_TIMEOUT_SECS: u64 = 2;
+
+/// Maximum number of messages being reassembled at once
+const MAX_PARTIALS: usize = 64;
+
+/// Split a message into datagrams
+pub fn split(id: u32, message: &[u8]) -> Vec<Vec<u8>> {
+    if message.len() <= FRAGMENT_SIZE {
+        let mut packet = Vec::with_capacity(message.len() + 1);
+        packet.push(COMPLETE);
+        packet.extend_from_slice(message);
+        return vec![packet];
+    }
+
+    let count = (message.len() + FRAGMENT_SIZE - 1) / FRAGMENT_SIZE;
+    message.chunks(FRAGMENT_SIZE).enumerate().map(|(index, chunk)| {
+        let mut packet = Vec::with_capacity(chunk.len() + FRAGMENT_HEADER);
+        packet.push(FRAGMENT);
+        packet.extend_from_slice(&[(id >> 24) as u8, (id >> 16) as u8, (id >> 8) as u8, id as u8]);
+        packet.extend_from_slice(&[(index >> 8) as u8, index as u8]);
+        packet.extend_from_slice(&[(count >> 8) as u8, count as u8]);
+        packet.extend_from_slice(chunk);
+        packet
+    }).collect()
+}
+
+/// Puts fragmented messages back together
+pub struct Reassembler {
+    partials: HashMap<(SocketAddr, u32), Partial>,
+}
+
+struct Partial {
+    fragments: Vec<Option<Vec<u8>>>,
+    received: usize,
+    started: Instant,
+}
+
+impl Reassembler {
+    pub fn new() -> Self {
+        Reassembler { partials: HashMap::new() }
+    }
+
+    /// Add a received datagram, returning the message once it is complete
+    pub fn push(&mut self, addr: &SocketAddr, packet: &[u8]) -> Option<Vec<u8>> {
+        match packet.first() {
+            Some(&COMPLETE) => return Some(packet[1..].to_vec()),
+            Some(&FRAGMENT) if packet.len() > FRAGMENT_HEADER => (),
+            _ => {
+                warn!("Dropping malformed packet from {}", addr);
+                return None;
+            },
+        }
+
+        let id = (packet[1] as u32) << 24 | (packet[2] as u32) << 16 |
+                 (packet[3] as u32) << 8 | packet[4] as u32;
+        let index = (packet[5] as usize) << 8 | packet[6] as usize;
+        let count = (packet[7] as usize) << 8 | packet[8] as usize;
+        if index >= count || count * FRAGMENT_SIZE > MAX_MESSAGE_SIZE + FRAGMENT_SIZE {
+            warn!("Dropping malformed fragment from {}", addr);
+            return None;
+        }
+
+        self.expire();
+        if self.partials.len() >= MAX_PARTIALS && !self.partials.contains_key(&(*addr, id)) {
+            warn!("Too many partial messages, dropping fragment from {}", addr);
+            return None;
+        }
+
+        let complete = {
+            let partial = self.partials.entry((*addr, id)).or_insert_with(|| Partial {
+                fragments: vec![None; count],
+                received: 0,
+                started: Instant::now(),
+            });
+
+            if partial.fragments.len() != count {
+                warn!("Fragment count mismatch from {}", addr);
+                return None;
+            }
+
+            if partial.fragments[index].is_none() {
+                partial.fragments[index] = Some(packet[FRAGMENT_HEADER..].to_vec());
+                partial.received += 1;
+            }
+
+            partial.received == count
+        };
+
+        if complete {
+            self.partials.remove(&(*addr, id)).map(|partial| {
+                partial.fragments.into_iter()
+                    .flat_map(|fragment| fragment.unwrap().into_iter())
+                    .collect()
+            })
+        } else {
+            None
+        }
+    }
+
+    fn expire(&mut self) {
+        let timeout = Duration::from_secs(REASSEMBLY_TIMEOUT_SECS);
+        let expired = self.partials.iter()
+            .filter(|&(_, partial)| partial.started.elapsed() > timeout)
+            .map(|(key, _)| *key)
+            .collect::<Vec<_>>();
+
+        for key in expired {
+            debug!("Gave up reassembling message {} from {}", key.1, key.0);
+            self.partials.remove(&key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::SocketAddr;
+
+    fn addr() -> SocketAddr {
+        "192.0.2.1:24242".parse().unwrap()
+    }
+
+    fn message(len: usize) -> Vec<u8> {
+        (0..len).map(|i| i as u8).collect()
+    }
+
+    #[test]
+    fn small_message_is_not_fragmented() {
+        let packets = split(1, &message(10));
+        assert_eq!(packets.len(), 1);
+        assert_eq!(Reassembler::new().push(&addr(), &packets[0]), Some(message(10)));
+    }
+
+    #[test]
+    fn reassembles_in_order() {
+        let msg = message(FRAGMENT_SIZE * 2 + 10);
+        let packets = split(7, &msg);
+        assert_eq!(packets.len(), 3);
+
+        let mut reassembler = Reassembler::new();
+        assert_eq!(reassembler.push(&addr(), &packets[0]), None);
+        assert_eq!(reassembler.push(&addr(), &packets[1]), None);
+        assert_eq!(reassembler.push(&addr(), &packets[2]), Some(msg));
+    }
+
+    #[test]
+    fn reassembles_out_of_order_with_duplicates() {
+        let msg = message(FRAGMENT_SIZE * 2 + 10);
+        let packets = split(7, &msg);
+
+        let mut reassembler = Reassembler::new();
+        assert_eq!(reassembler.push(&addr(), &packets[2]), None);
+        assert_eq!(reassembler.push(&addr(), &packets[0]), None);
+        assert_eq!(reassembler.push(&addr(), &packets[0]), None);
+        assert_eq!(reassembler.push(&addr(), &packets[1]), Some(msg));
+    }
+
+    #[test]
+    fn keeps_senders_apart() {
+        let msg = message(FRAGMENT_SIZE + 1);
+        let packets = split(7, &msg);
+        let other = "192.0.2.2:24242".parse().unwrap();
+
+        let mut reassembler = Reassembler::new();
+        assert_eq!(reassembler.push(&addr(), &packets[0]), None);
+        assert_eq!(reassembler.push(&other, &packets[1]), None);
+        assert_eq!(reassembler.push(&addr(), &packets[1]), Some(msg));
+    }
+
+    #[test]
+    fn drops_truncated_fragments() {
+        let packets = split(7, &message(FRAGMENT_SIZE + 1));
+        let mut reassembler = Reassembler::new();
+        assert_eq!(reassembler.push(&addr(), &[]), None);
+        assert_eq!(reassembler.push(&addr(), &packets[0][..FRAGMENT_HEADER]), None);
+        assert_eq!(reassembler.push(&addr(), &[FRAGMENT, 0, 0]), None);
+        assert_eq!(reassembler.push(&addr(), &[42, 1, 2, 3]), None);
+    }
+
+    #[test]
+    fn drops_bad_indices_and_oversized_messages() {
+        let mut reassembler = Reassembler::new();
+        // Index past the count
+        assert_eq!(reassembler.push(&addr(), &[FRAGMENT, 0, 0, 0, 1, 0, 2, 0, 2, 0]), None);
+
+        // More fragments than the largest message needs
+        let count = MAX_MESSAGE_SIZE / FRAGMENT_SIZE + 2;
+        let packet = [FRAGMENT, 0, 0, 0, 1, 0, 0, (count >> 8) as u8, count as u8, 0];
+        assert_eq!(reassembler.push(&addr(), &packet), None);
+        assert!(reassembler.partials.is_empty());
+    }
+
+    #[test]
+    fn drops_fragments_with_a_different_count() {
+        let packets = split(7, &message(FRAGMENT_SIZE * 2 + 1));
+        let mut reassembler = Reassembler::new();
+        assert_eq!(reassembler.push(&addr(), &packets[0]), None);
+
+        let mut packet = packets[1].clone();
+        packet[8] = 2;
+        assert_eq!(reassembler.push(&addr(), &packet), None);
+    }
+}
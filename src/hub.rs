@@ -1,17 +1,62 @@
 use io::*;
-use cluster::Cluster;
-use config_server::ConfigServer;
+use cluster::{Cluster, Layout, Focus, Index};
+use config_server::{self, ConfigServer, ClientEvent, Access};
+use route::{Routes, RouteStatus};
+use mdns::{MdnsDiscovery, Peer};
+use ctl::{self, ControlServer, ControlClient};
+use config::{self, Config, Source};
+use signal::{self, Signals};
+use netlink::NetlinkWatch;
+use sequence::{Sequence, Stream};
+use arbiter::{Arbiter, Claim, Rules};
+use util;
+use api::{self, ClusterView, Event, FocusStatus, PeerStatus, Reply};
 
 use mio::*;
 use ws::{WebSocket, Sender as WsSender};
 use serde_json;
 
-use std::io;
+use std::{io, cmp, process};
 use std::net::SocketAddr;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
 use std::thread;
+use std::sync::Arc;
 
 const HOST_EVENT: Token = Token(0);
 const NET_EVENT: Token = Token(1);
+const DISCOVERY_EVENT: Token = Token(2);
+const CONTROL_EVENT: Token = Token(3);
+const SIGNAL_EVENT: Token = Token(4);
+const NETLINK_EVENT: Token = Token(5);
+/// Control connections get the tokens from here on
+const CONTROL_CLIENT_EVENTS: usize = 16;
+
+const PROBE_INTERVAL_MS: u64 = 1000;
+const OWNERSHIP_INTERVAL_MS: u64 = 1000;
+const GRAB_CHECK_INTERVAL_MS: u64 = 500;
+const FOCUS_STATUS_INTERVAL_MS: u64 = 100;
+const ANNOUNCE_MIN_MS: u64 = 1000;
+const ANNOUNCE_MAX_MS: u64 = 30000;
+/// Other clients usually hold their grabs briefly (eg. while a menu
+/// is open), so crossings that fail to grab are retried with backoff
+const GRAB_RETRY_MIN_MS: u64 = 10;
+const GRAB_RETRY_MAX_MS: u64 = 1000;
+/// Wait for a burst of address changes to settle
+const NETWORK_SETTLE_MS: u64 = 500;
+
+#[derive(Clone, Copy, Debug)]
+pub enum Timer {
+    Probe,
+    FlushFocus,
+    Ownership,
+    GrabCheck,
+    GrabRetry,
+    Announce,
+    NetworkChange,
+    /// A control connection has to send its request in time
+    ControlRequest(usize),
+}
 
 pub struct Hub<H, N> where
     H: HostInterface + Evented,
@@ -22,6 +67,100 @@ pub struct Hub<H, N> where
     net: N,
     clients: Option<WsSender>,
     state: State,
+    routes: Routes,
+    discovery: Option<MdnsDiscovery>,
+    control: Option<ControlServer>,
+    control_clients: HashMap<usize, ControlClient>,
+    next_control_client: usize,
+    signals: Option<Signals>,
+    netlink: Option<NetlinkWatch>,
+    network_change_scheduled: bool,
+    ui_url: String,
+    source: Source,
+    config: Config,
+    focus: FocusUpdates,
+    status: StatusUpdates,
+    announce: Announcements,
+    grab: GrabRetries,
+    sequence: Sequence,
+    /// Which host controls focus
+    arbiter: Arbiter,
+    /// Input forwarded to the focused screen
+    sent_input: HeldInput,
+    /// Input received from peers and replayed on the local screen
+    injected_input: HeldInput,
+}
+
+/// Focus updates waiting to be sent
+struct FocusUpdates {
+    pending: Option<Focus>,
+    flush_scheduled: bool,
+    sent_index: Option<Index>,
+    changed: bool,
+    remote: bool,
+    /// When a peer last moved focus or sent input
+    remote_at: Instant,
+    /// When a claim was last sent to every peer
+    claim_sent: Instant,
+    pointer_locked: bool,
+}
+
+/// Status last pushed to config clients
+struct StatusUpdates {
+    focus_index: Option<Index>,
+    focus_sent: Instant,
+    pending_focus: Option<Focus>,
+    /// Whether each remote screen was reachable, by name
+    online: HashMap<String, bool>,
+}
+
+/// Re-announcements while waiting for peers, backing off exponentially
+struct Announcements {
+    delay_ms: u64,
+    scheduled: bool,
+}
+
+/// Retries of a crossing that failed to grab the local input
+struct GrabRetries {
+    delay_ms: u64,
+    scheduled: bool,
+    /// Whether the failure has been reported since the last success
+    reported: bool,
+}
+
+/// Keys and buttons that are still pressed, so
+/// they can be released if their source goes away
+#[derive(Default)]
+struct HeldInput {
+    keys: Vec<u64>,
+    buttons: Vec<u32>,
+}
+
+impl HeldInput {
+    fn key(&mut self, event: &KeyEvent) {
+        if event.state {
+            if !self.keys.contains(&event.key) { self.keys.push(event.key) }
+        } else {
+            self.keys.retain(|&key| key != event.key);
+        }
+    }
+
+    fn button(&mut self, event: &ButtonEvent) {
+        if event.state {
+            if !self.buttons.contains(&event.button) { self.buttons.push(event.button) }
+        } else {
+            self.buttons.retain(|&button| button != event.button);
+        }
+    }
+
+    /// Release events for everything that is held
+    fn release(&mut self) -> Vec<NetEvent> {
+        let keys = self.keys.drain(..)
+            .map(|key| NetEvent::Key(KeyEvent { key: key, state: false }));
+        let buttons = self.buttons.drain(..)
+            .map(|button| NetEvent::Button(ButtonEvent { button: button, state: false }));
+        keys.chain(buttons).collect()
+    }
 }
 
 #[derive(PartialEq, Clone, Copy, Debug)]
@@ -35,10 +174,17 @@ impl<H, N> Hub<H, N> where
     H: HostInterface + Evented,
     N: NetInterface + Evented,
 {
-    pub fn new(host: H, net: N) -> io::Result<Self> {
-        let (width, height) = host.screen_size();
-        let (x, y) = host.cursor_pos();
-        let cluster = Cluster::new(width, height, x, y);
+    /// Without mDNS `discovery` peers are discovered
+    /// over the network interface's multicast group
+    pub fn new(host: H, net: N, discovery: Option<MdnsDiscovery>, source: Source, config: Config) -> io::Result<Self> {
+        let host_id = try!(util::host_id());
+        let rules = config.rules();
+        let mut cluster = Cluster::new(&host, host_id, rules, net.local_addrs());
+        cluster.set_entry(config.entry());
+        cluster.set_relative_key(config.hotkeys.relative_toggle);
+        cluster.update_barriers(&host);
+        host.set_escape_keys(&config.hotkeys.escape);
+        let arbiter = Arbiter::new(rules);
 
         Ok(Hub {
             cluster: cluster,
@@ -46,6 +192,46 @@ impl<H, N> Hub<H, N> where
             net: net,
             clients: None,
             state: State::Connecting,
+            routes: Routes::new(),
+            discovery: discovery,
+            control: None,
+            control_clients: HashMap::new(),
+            next_control_client: 0,
+            signals: None,
+            netlink: None,
+            network_change_scheduled: false,
+            ui_url: String::new(),
+            source: source,
+            config: config,
+            focus: FocusUpdates {
+                pending: None,
+                flush_scheduled: false,
+                sent_index: None,
+                changed: false,
+                remote: false,
+                remote_at: Instant::now(),
+                claim_sent: Instant::now(),
+                pointer_locked: false,
+            },
+            status: StatusUpdates {
+                focus_index: None,
+                focus_sent: Instant::now(),
+                pending_focus: None,
+                online: HashMap::new(),
+            },
+            announce: Announcements {
+                delay_ms: ANNOUNCE_MIN_MS,
+                scheduled: false,
+            },
+            grab: GrabRetries {
+                delay_ms: GRAB_RETRY_MIN_MS,
+                scheduled: false,
+                reported: false,
+            },
+            sequence: Sequence::new(host_id),
+            arbiter: arbiter,
+            sent_input: HeldInput::default(),
+            injected_input: HeldInput::default(),
         })
     }
 
@@ -63,89 +249,1105 @@ impl<H, N> Hub<H, N> where
                                  EventSet::writable(),
                                  PollOpt::oneshot()));
 
+        if let Some(ref discovery) = self.discovery {
+            try!(event_loop.register(discovery,
+                                     DISCOVERY_EVENT,
+                                     EventSet::readable(),
+                                     PollOpt::level()));
+        }
+
+        let control = try!(ControlServer::open(&ctl::socket_path()));
+        try!(event_loop.register(&control,
+                                 CONTROL_EVENT,
+                                 EventSet::readable(),
+                                 PollOpt::level()));
+        self.control = Some(control);
+
+        let signals = try!(Signals::open(&[signal::SIGHUP, signal::SIGINT, signal::SIGTERM]));
+        try!(event_loop.register(&signals,
+                                 SIGNAL_EVENT,
+                                 EventSet::readable(),
+                                 PollOpt::level()));
+        self.signals = Some(signals);
+
+        match NetlinkWatch::open() {
+            Ok(netlink) => {
+                try!(event_loop.register(&netlink,
+                                         NETLINK_EVENT,
+                                         EventSet::readable(),
+                                         PollOpt::level()));
+                self.netlink = Some(netlink);
+            },
+            Err(err) => warn!("Not watching for network changes: {}", err),
+        }
+
+        let addr = self.config.config_server.addr.clone();
+        let loopback = addr.parse::<SocketAddr>().map(|addr| config::is_loopback(&addr.ip())).unwrap_or(false);
+        let token = if self.config.config_server.auth {
+            Some(try!(config_server::create_token(&config_server::token_path())))
+        } else {
+            None
+        };
+
+        self.ui_url = match token {
+            Some(ref token) => format!("http://{}/?token={}", addr, token),
+            None => format!("http://{}/", addr),
+        };
+
+        let access = Arc::new(Access { token: token, loopback_only: loopback });
         let channel = event_loop.channel();
-        let socket = WebSocket::new(move |out| {
-            ConfigServer::new(out, channel.clone())
-        }).unwrap();
+        let socket = try!(WebSocket::new(move |out| {
+            ConfigServer::new(out, channel.clone(), access.clone())
+        }).map_err(|err| io::Error::new(io::ErrorKind::Other, err)));
+
+        event_loop.timeout_ms(Timer::Probe, PROBE_INTERVAL_MS).unwrap();
+        event_loop.timeout_ms(Timer::Ownership, OWNERSHIP_INTERVAL_MS).unwrap();
+        event_loop.timeout_ms(Timer::GrabCheck, GRAB_CHECK_INTERVAL_MS).unwrap();
 
         self.clients = Some(socket.broadcaster());
-        thread::spawn(move || {
-            socket.listen("127.0.0.1:3012").unwrap();
-            warn!("Configuration server has shutdown");
+        // The session token is left out of the logs, `elemeld ctl status` shows the full URL
+        info!("Serving the configuration UI on http://{}/", addr);
+        let server = thread::spawn(move || {
+            if let Err(err) = socket.listen(&addr[..]) {
+                error!("Failed to serve the configuration UI on {}: {}", addr, err);
+                process::exit(1);
+            }
+            info!("Configuration server has shutdown");
         });
 
         try!(event_loop.run(self));
-        // TODO: Should probably kill spawned threads
+        if server.join().is_err() {
+            error!("Configuration server stopped unexpectedly");
+        }
+
         Ok(())
     }
 
-    pub fn host_event(&mut self, event: HostEvent) {
+    /// Give up focus and leave the cluster without
+    /// leaving any input grabbed or keys held down
+    fn shutdown(&mut self, event_loop: &mut EventLoop<Self>) {
+        info!("Shutting down");
+        self.release_sent_input();
+        self.release_injected_input();
+        self.host.ungrab_cursor();
+        self.host.ungrab_keyboard();
+        self.host.set_barriers(Barriers::default());
+
+        if let Err(err) = self.send_to_all(&NetEvent::Leave) {
+            error!("Failed to leave cluster: {}", err);
+        }
+
+        if let Some(clients) = self.clients.take() {
+            if let Err(err) = clients.shutdown() {
+                error!("Failed to stop configuration server: {}", err);
+            }
+        }
+
+        event_loop.shutdown();
+    }
+
+    /// Release any keys or buttons forwarded to the focused screen
+    fn release_sent_input(&mut self) {
+        if !self.cluster.locally_focused() {
+            let index = self.cluster.focused_screen_index();
+            for event in self.sent_input.release() {
+                self.send_to_screen(index, &event);
+            }
+        }
+    }
+
+    /// Release any keys or buttons pressed on the local screen by peers
+    fn release_injected_input(&mut self) {
+        for event in self.injected_input.release() {
+            match event {
+                NetEvent::Key(event) => self.host.send_event(HostEvent::Key(event)),
+                NetEvent::Button(event) => self.host.send_event(HostEvent::Button(event)),
+                _ => (),
+            }
+        }
+    }
+
+    /// Move to a new connection state, re-announcing
+    /// with backoff until a peer answers
+    fn set_state(&mut self, event_loop: &mut EventLoop<Self>, state: State) {
+        if state == self.state {
+            return;
+        }
+
+        info!("Connection state {:?} -> {:?}", self.state, state);
+        self.state = state;
+        match state {
+            State::Connecting => (),
+            State::Waiting => {
+                // Input is ignored until reconnected, so don't leave it grabbed
+                self.reclaim_focus();
+                self.arbiter.clear();
+                self.schedule_announce(event_loop);
+            },
+            State::Connected => self.announce.delay_ms = ANNOUNCE_MIN_MS,
+        }
+    }
+
+    fn schedule_announce(&mut self, event_loop: &mut EventLoop<Self>) {
+        if !self.announce.scheduled {
+            event_loop.timeout_ms(Timer::Announce, self.announce.delay_ms).unwrap();
+            self.announce.scheduled = true;
+        }
+    }
+
+    /// Ask peers to connect, over mDNS or the multicast group
+    fn announce(&mut self) {
+        let result = match self.discovery {
+            Some(ref discovery) => discovery.announce()
+                .and_then(|_| discovery.browse()),
+            None => self.net.send_to_all(&NetEvent::Connect(self.cluster.layout().clone())),
+        };
+
+        if let Err(err) = result {
+            self.report_error(format!("Failed to connect: {}", err));
+        }
+    }
+
+    /// Announce again if no peer has answered yet
+    fn reannounce(&mut self, event_loop: &mut EventLoop<Self>) {
+        self.announce.scheduled = false;
+        if self.state != State::Waiting {
+            return;
+        }
+
+        self.announce();
+        self.announce.delay_ms = cmp::min(self.announce.delay_ms * 2, ANNOUNCE_MAX_MS);
+        debug!("Announcing again in {} ms", self.announce.delay_ms);
+        self.schedule_announce(event_loop);
+    }
+
+    /// Advertise the local screen's new addresses after a network change
+    fn network_changed(&mut self) {
+        self.network_change_scheduled = false;
+        match self.net.refresh() {
+            Ok(true) => (),
+            Ok(false) => return,
+            Err(err) => {
+                self.report_error(format!("Failed to refresh network interfaces: {}", err));
+                return;
+            },
+        }
+
+        let addrs = self.net.local_addrs();
+        info!("Local addresses changed: {:?}", addrs);
+        if let Some(ref mut discovery) = self.discovery {
+            if let Err(err) = discovery.refresh(addrs.iter().map(|addr| addr.ip()).collect()) {
+                error!("Failed to refresh discovery: {}", err);
+            }
+        }
+
+        if !self.cluster.set_local_addrs(addrs) {
+            return;
+        }
+
+        self.broadcast_cluster();
+        match self.state {
+            State::Connecting => (),
+            State::Waiting => {
+                self.announce.delay_ms = ANNOUNCE_MIN_MS;
+                self.announce();
+            },
+            State::Connected => {
+                let result = match self.discovery {
+                    Some(ref discovery) => discovery.announce(),
+                    None => Ok(Some(())),
+                }.and_then(|_| self.send_to_all(&self.sequenced(NetEvent::Cluster(self.cluster.layout().clone()))));
+
+                if let Err(err) = result {
+                    self.report_error(format!("Failed to send cluster: {}", err));
+                }
+            },
+        }
+    }
+
+    /// Local hardware was used while a peer drives the local screen, so take
+    /// control back unless the peer has used it within the grace period
+    fn reclaim_by_input(&mut self, event_loop: &mut EventLoop<Self>) {
+        let grace = Duration::from_millis(self.config.reclaim.grace_ms);
+        if self.focus.remote_at.elapsed() < grace {
+            return;
+        }
+
+        if !self.arbiter.allows(&self.local_claim(true)) {
+            return;
+        }
+
+        info!("Local input reclaimed focus");
+        self.release_injected_input();
+
+        // Announce the handover even though the focused screen doesn't change
+        let (x, y) = self.host.cursor_pos();
+        let focus = self.cluster.local_focus(x, y);
+        self.focus.sent_index = None;
+        self.focus_changed(event_loop, focus, true);
+    }
+
+    /// Force focus back to the local screen and tell the peers.
+    /// This always applies locally, whichever host controls focus.
+    fn return_focus(&mut self, event_loop: &mut EventLoop<Self>, reason: &str) {
+        if self.cluster.locally_focused() {
+            return;
+        }
+
+        warn!("{}, returning focus to the local screen", reason);
+        self.release_sent_input();
+        let local = self.cluster.local_index();
+        if let Ok(focus) = self.cluster.focus_screen(&self.host, local) {
+            self.focus.sent_index = None;
+            self.focus_changed(event_loop, focus, true);
+        }
+    }
+
+    /// Return focus if the focused peer has stopped answering probes.
+    /// Only the host holding the grabs watches, bystanders follow its claim.
+    fn check_focused_peer(&mut self, event_loop: &mut EventLoop<Self>) {
+        if self.focus.remote || self.cluster.locally_focused() {
+            return;
+        }
+
+        let addrs = match self.cluster.screen(self.cluster.focused_screen_index()) {
+            Some(screen) => screen.addrs(),
+            None => return,
+        };
+
+        if !addrs.is_empty() && addrs.iter().all(|addr| self.routes.is_dead(addr)) {
+            self.return_focus(event_loop, "Focused screen stopped responding");
+        }
+    }
+
+    /// Focus the local screen if a remote one has focus
+    fn reclaim_focus(&mut self) {
+        if self.cluster.locally_focused() {
+            return;
+        }
+
+        // Best effort, as the peer may not be reachable
+        let local = self.cluster.local_index();
+        self.release_sent_input();
+        self.focus.sent_index = None;
+        self.focus.remote = false;
+        if let Ok(focus) = self.cluster.focus_screen(&self.host, local) {
+            self.focus_status(focus);
+        }
+    }
+
+    /// A peer has shut down, so take focus back if it was on its screen
+    /// and this host controls focus. Bystanders follow the controller.
+    fn peer_left(&mut self, event_loop: &mut EventLoop<Self>, addr: &SocketAddr) {
+        let index = match self.cluster.find_addr(addr) {
+            Some(index) if index != self.cluster.local_index() => index,
+            _ => return,
+        };
+
+        info!("Screen {} left the cluster", self.cluster.screen(index).unwrap().name());
+
+        if self.cluster.locally_focused() && self.focus.remote {
+            self.release_injected_input();
+        }
+
+        if let Some(host_id) = self.cluster.screen(index).and_then(|screen| screen.host_id()) {
+            self.arbiter.release(host_id);
+        }
+
+        if self.cluster.focused_screen_index() == index && !self.focus.remote {
+            self.return_focus(event_loop, "Focused screen left the cluster");
+        }
+    }
+
+    pub fn host_event(&mut self, event_loop: &mut EventLoop<Self>, event: HostEvent) {
+        if let HostEvent::Escape = event {
+            self.return_focus(event_loop, "Escape hotkey pressed");
+            return;
+        }
+
         if self.state != State::Connected { return }
 
-        if let Some(event) = self.cluster.process_host_event(&self.host, event) {
+        // Only a screen driven by a peer is reclaimed, never the controller's own input
+        if self.config.reclaim.enabled && self.focus.remote && self.cluster.locally_focused() {
             match event {
-                // Global events
-                NetEvent::Focus(_) => {
-                    if let Err(err) = self.net.send_to_all(&event) {
-                        error!("Failed to send event to cluster: {}", err);
-                        self.state = State::Waiting;
-                    }
-                },
-                // Focused events
-                event => {
-                    let addr = self.cluster.focused_screen().default_route();
-                    if let Err(err) = self.net.send_to(&event, addr) {
-                        error!("Failed to send event to {}: {}", addr, err);
-                    }
+                HostEvent::Motion(_) | HostEvent::Activity => {
+                    self.reclaim_by_input(event_loop);
+                    return;
                 },
+                _ => (),
+            }
+        }
+
+        match event {
+            // Another host controls focus, so local input mustn't move it
+            HostEvent::Motion(_) | HostEvent::Barrier(_) | HostEvent::Key(_) | HostEvent::Button(_) => {
+                if !self.arbiter.allows(&self.local_claim(false)) {
+                    return;
+                }
+            },
+            _ => (),
+        }
+
+        // Focus stays local until a failed crossing's retry succeeds
+        if let HostEvent::Barrier(_) = event {
+            if self.grab.scheduled {
+                return;
+            }
+        }
+
+        let event = match self.cluster.process_host_event(&self.host, event) {
+            Ok(Some(event)) => event,
+            Ok(None) => return,
+            Err(err) => {
+                self.grab_failed(event_loop, err);
+                return;
+            },
+        };
+
+        match event {
+            // Global events
+            NetEvent::Focus(focus) => self.focus_changed(event_loop, focus, false),
+            // Focused events
+            event => {
+                match event {
+                    NetEvent::Key(ref event) => self.sent_input.key(event),
+                    NetEvent::Button(ref event) => self.sent_input.button(event),
+                    _ => (),
+                }
+
+                // Claim control first, so peers accept the input after going idle
+                self.keep_control();
+                let index = self.cluster.focused_screen_index();
+                self.send_to_screen(index, &event);
+            },
+        }
+    }
+
+    /// Report a crossing that failed to grab the local input,
+    /// once until it succeeds, and schedule a retry
+    fn grab_failed(&mut self, event_loop: &mut EventLoop<Self>, err: String) {
+        if self.grab.reported {
+            debug!("{}", err);
+        } else {
+            self.report_error(err);
+            self.grab.reported = true;
+        }
+
+        if !self.grab.scheduled {
+            event_loop.timeout_ms(Timer::GrabRetry, self.grab.delay_ms).unwrap();
+            self.grab.scheduled = true;
+        }
+    }
+
+    fn retry_grab(&mut self, event_loop: &mut EventLoop<Self>) {
+        self.grab.scheduled = false;
+        let result = if self.state == State::Connected && self.arbiter.allows(&self.local_claim(false)) {
+            self.cluster.retry_crossing(&self.host)
+        } else {
+            None
+        };
+
+        match result {
+            Some(Ok(focus)) => {
+                info!("Grabbed the local input, crossing to screen {}", focus.index());
+                self.grab.delay_ms = GRAB_RETRY_MIN_MS;
+                self.grab.reported = false;
+                self.focus_changed(event_loop, focus, false);
+            },
+            Some(Err(err)) => {
+                self.grab.delay_ms = cmp::min(self.grab.delay_ms * 2, GRAB_RETRY_MAX_MS);
+                self.grab_failed(event_loop, err);
+            },
+            // The cursor has moved away, so give up on the crossing
+            None => {
+                self.grab.delay_ms = GRAB_RETRY_MIN_MS;
+                self.grab.reported = false;
+            },
+        }
+    }
+
+    /// The local input couldn't be grabbed when a peer moved focus off
+    /// the local screen, so focus stayed here. Tell the peers about it.
+    fn focus_kept(&mut self, event_loop: &mut EventLoop<Self>, err: String) {
+        self.report_error(err);
+        let focus = self.cluster.focus();
+        self.focus.sent_index = None;
+        self.focus_changed(event_loop, focus, true);
+    }
+
+    /// Crossings are sent to every peer straight away, otherwise
+    /// updates are coalesced and sent only to the focused screen.
+    /// A `takeover` was asked for explicitly rather than by moving the cursor.
+    fn focus_changed(&mut self, event_loop: &mut EventLoop<Self>, focus: Focus, takeover: bool) {
+        let claim = self.local_claim(takeover);
+        self.arbiter.record(&claim);
+        self.focus.changed = true;
+        self.focus.remote = false;
+        self.focus_status(focus);
+
+        if self.focus.sent_index != Some(focus.index()) {
+            // The screen being left gets its last position before the crossing
+            self.send_pending_focus();
+            self.focus.sent_index = Some(focus.index());
+            if let Err(err) = self.claim_to_all(claim, focus) {
+                self.report_error(format!("Failed to send event to cluster: {}", err));
+                self.set_state(event_loop, State::Waiting);
             }
+            return;
+        }
+
+        self.focus.pending = Some(focus);
+        if !self.focus.flush_scheduled {
+            let delay = 1000 / cmp::max(self.config.pointer.focus_rate, 1) as u64;
+            event_loop.timeout_ms(Timer::FlushFocus, delay).unwrap();
+            self.focus.flush_scheduled = true;
         }
+
+        self.keep_control();
     }
 
-    pub fn net_event(&mut self, event: NetEvent, addr: &SocketAddr) {
+    /// Stay the controller while forwarding input, reminding every peer at least
+    /// twice per idle time so none of them hands control to another host
+    fn keep_control(&mut self) {
+        let claim = self.local_claim(false);
+        self.arbiter.record(&claim);
+
+        let interval = Duration::from_millis(self.cluster.rules().idle_ms / 2);
+        if self.focus.claim_sent.elapsed() < interval {
+            return;
+        }
+
+        let focus = self.cluster.focus();
+        if let Err(err) = self.claim_to_all(claim, focus) {
+            self.report_error(format!("Failed to send event to cluster: {}", err));
+        }
+    }
+
+    fn claim_to_all(&mut self, claim: Claim, focus: Focus) -> io::Result<Option<()>> {
+        self.focus.claim_sent = Instant::now();
+        self.send_to_all(&self.sequenced(NetEvent::Claim(claim, focus)))
+    }
+
+    fn flush_focus(&mut self) {
+        self.focus.flush_scheduled = false;
+        self.send_pending_focus();
+    }
+
+    /// Send the latest coalesced update to the screen it's on
+    fn send_pending_focus(&mut self) {
+        if let Some(focus) = self.focus.pending.take() {
+            if focus.index() != self.cluster.local_index() {
+                let event = self.sequenced(NetEvent::Claim(self.local_claim(false), focus));
+                self.send_to_screen(focus.index(), &event);
+            }
+        }
+    }
+
+    /// Let every peer know where focus is after it has changed,
+    /// at a much lower rate than the focused screens are updated
+    fn send_ownership(&mut self) {
+        if let Some(focus) = self.status.pending_focus.take() {
+            self.send_focus_status(focus);
+        }
+
+        if !self.focus.changed {
+            return;
+        }
+
+        self.focus.changed = false;
+        let focus = self.cluster.focus();
+        let claim = self.local_claim(false);
+        if let Err(err) = self.claim_to_all(claim, focus) {
+            self.report_error(format!("Failed to send event to cluster: {}", err));
+        }
+    }
+
+    /// While a remote host controls the local screen, tell it to switch
+    /// to relative motion when a local application locks the pointer
+    fn check_pointer_lock(&mut self) {
+        let locked = self.cluster.locally_focused() && self.focus.remote &&
+            self.host.pointer_locked();
+
+        if locked == self.focus.pointer_locked {
+            return;
+        }
+
+        self.focus.pointer_locked = locked;
+        if !locked && self.cluster.locally_focused() {
+            // Absolute tracking resumes from wherever the application left the cursor
+            let (x, y) = self.host.cursor_pos();
+            let focus = self.cluster.local_focus(x, y);
+            let claim = self.local_claim(false);
+            if self.arbiter.claim(&claim) {
+                if let Err(err) = self.claim_to_all(claim, focus) {
+                    self.report_error(format!("Failed to send event to cluster: {}", err));
+                }
+            }
+        }
+
+        if let Err(err) = self.send_to_all(&NetEvent::PointerGrab(locked)) {
+            self.report_error(format!("Failed to send event to cluster: {}", err));
+        }
+    }
+
+    fn send_to_screen(&self, index: Index, event: &NetEvent) {
+        let addrs = match self.cluster.screen(index) {
+            Some(screen) => screen.addrs(),
+            None => return,
+        };
+
+        match self.routes.best(&addrs) {
+            Some(addr) => if let Err(err) = self.net.send_to(event, addr) {
+                error!("Failed to send event to {}: {}", addr, err);
+            },
+            None => error!("No route to screen {}", index),
+        }
+    }
+
+    pub fn net_event(&mut self, event_loop: &mut EventLoop<Self>, event: NetEvent, addr: &SocketAddr) {
         match event {
             // Initialization events
-            NetEvent::Connect(cluster) => {
+            NetEvent::Connect(ref cluster) if self.is_removed(cluster) => {
+                debug!("Ignoring cluster from removed screen at {}", addr);
+            },
+            NetEvent::Connect(mut cluster) => {
+                cluster.localize_scopes(addr);
                 self.cluster.merge(cluster);
-                self.broadcast_net_event(&NetEvent::Cluster(self.cluster.clone()));
-                match self.net.send_to_all(&NetEvent::Cluster(self.cluster.clone())) {
-                    Ok(_) => self.state = State::Connected,
-                    Err(err) => error!("Failed to connect: {}", err),
+                self.cluster.update_barriers(&self.host);
+                self.broadcast_cluster();
+                match self.send_to_all(&self.sequenced(NetEvent::Cluster(self.cluster.layout().clone()))) {
+                    Ok(_) => self.set_state(event_loop, State::Connected),
+                    Err(err) => self.report_error(format!("Failed to connect: {}", err)),
                 };
             },
-            NetEvent::Cluster(cluster) => {
-                self.cluster.replace(&self.host, cluster);
-                self.broadcast_net_event(&NetEvent::Cluster(self.cluster.clone()));
-                self.state = State::Connected;
-            },
             NetEvent::RequestCluster => {
-                if let Err(err) = self.net.send_to(&NetEvent::Cluster(self.cluster.clone()), addr) {
-                    error!("Failed to passively connect: {}", err);
+                if let Err(err) = self.net.send_to(&self.sequenced(NetEvent::Cluster(self.cluster.layout().clone())), addr) {
+                    self.report_error(format!("Failed to passively connect: {}", err));
                 }
             },
-            NetEvent::Screens(screens) => {
-                self.cluster.set_screens(screens);
+            NetEvent::PointerGrab(grabbed) => {
+                self.cluster.set_relative_grab(grabbed);
             },
-            // Global events
-            NetEvent::Focus(focus) => {
-                self.cluster.refocus(&self.host, focus);
+            // Probe events
+            NetEvent::Ping(id) => {
+                if let Err(err) = self.net.send_to(&NetEvent::Pong(id), addr) {
+                    error!("Failed to reply to probe from {}: {}", addr, err);
+                }
+            },
+            NetEvent::Pong(id) => {
+                self.routes.pong(id);
+            },
+            NetEvent::Leave => {
+                self.peer_left(event_loop, addr);
+            },
+            NetEvent::Sequenced(stamp, event) => {
+                let stream = match *event {
+                    NetEvent::Claim(..) => Stream::Focus,
+                    NetEvent::Cluster(..) => Stream::Layout,
+                    ref other => {
+                        debug!("Dropping unexpected sequenced {:?} from {}", other, addr);
+                        return;
+                    },
+                };
+
+                if self.sequence.accept(stream, &stamp) {
+                    self.sequenced_event(event_loop, *event, addr);
+                } else {
+                    debug!("Dropping stale {:?} event from {}", stream, addr);
+                }
+            },
+            // Ordering can't be checked without a stamp
+            NetEvent::Claim(..) | NetEvent::Cluster(..) => {
+                debug!("Dropping unsequenced event from {}", addr);
+            },
+            // Focused events
+            _ if !self.is_controller(addr) => {
+                debug!("Dropping input from {}, which doesn't control focus", addr);
             },
-            // Focued events
             event => if let Some(event) = self.cluster.process_net_event(event) {
+                self.focus.remote_at = Instant::now();
+                match event {
+                    HostEvent::Key(ref event) => self.injected_input.key(event),
+                    HostEvent::Button(ref event) => self.injected_input.button(event),
+                    _ => (),
+                }
+
                 self.host.send_event(event);
             },
         }
     }
 
-    fn send_net_event(&self, event: &NetEvent, sender: &WsSender) {
-        let msg = serde_json::to_string(&event).unwrap();
-        sender.send(msg).unwrap();
+    /// Whether the screen at the address belongs to the host controlling focus
+    fn is_controller(&self, addr: &SocketAddr) -> bool {
+        let host_id = self.cluster.find_addr(addr)
+            .and_then(|index| self.cluster.screen(index))
+            .and_then(|screen| screen.host_id());
+        host_id.is_some() && host_id == self.arbiter.controller()
+    }
+
+    /// Whether a layout comes from a host whose screen was removed
+    fn is_removed(&self, layout: &Layout) -> bool {
+        layout.local_host_id().map_or(false, |host_id| self.cluster.is_removed(host_id))
+    }
+
+    /// Follow the rules shared by a peer, warning if they differ from the local config
+    fn rules_changed(&mut self, prev: Rules, addr: &SocketAddr) {
+        let rules = self.cluster.rules();
+        if rules != prev && rules != self.config.rules() {
+            warn!("Screen at {} set arbitration policy {:?}, idle after {} ms, \
+                   which differs from the local config", addr, rules.policy, rules.idle_ms);
+        }
+
+        self.arbiter.set_rules(rules);
+    }
+
+    /// Handle a focus or layout event that isn't older than one already applied
+    fn sequenced_event(&mut self, event_loop: &mut EventLoop<Self>, event: NetEvent, addr: &SocketAddr) {
+        match event {
+            NetEvent::Cluster(ref cluster) if self.is_removed(cluster) => {
+                debug!("Ignoring cluster from removed screen at {}", addr);
+            },
+            NetEvent::Cluster(mut cluster) => {
+                cluster.localize_scopes(addr);
+                let rules = self.cluster.rules();
+                let result = self.cluster.replace(&self.host, cluster);
+                if let Ok(false) = result {
+                    warn!("Ignoring cluster from {} without the local screen", addr);
+                    return;
+                }
+
+                self.rules_changed(rules, addr);
+                self.cluster.update_barriers(&self.host);
+                self.broadcast_cluster();
+                self.set_state(event_loop, State::Connected);
+                if let Err(err) = result {
+                    self.focus_kept(event_loop, err);
+                }
+            },
+            NetEvent::Claim(claim, focus) => {
+                let controlling = self.arbiter.controller() == Some(self.cluster.host_id());
+                if !self.arbiter.claim(&claim) {
+                    debug!("Rejecting focus claim from host {:016x} at {}", claim.host_id, addr);
+                    return;
+                }
+
+                // Our own claim lost, so stop driving the focused screen
+                if controlling && claim.host_id != self.cluster.host_id() {
+                    info!("Host {:016x} took control of focus", claim.host_id);
+                    self.release_sent_input();
+                    self.focus.pending = None;
+                }
+
+                // Another host has moved focus, so our next update is a handover
+                self.focus.sent_index = None;
+                self.focus.remote = true;
+                self.focus.remote_at = Instant::now();
+                if let Err(err) = self.cluster.refocus(&self.host, focus) {
+                    self.focus_kept(event_loop, err);
+                    return;
+                }
+
+                let focus = self.cluster.focus();
+                self.focus_status(focus);
+            },
+            _ => unreachable!(),
+        }
+    }
+
+    /// This host's claim to control focus
+    fn local_claim(&self, takeover: bool) -> Claim {
+        self.arbiter.local_claim(self.cluster.host_id(), self.config.arbitration.priority, takeover)
+    }
+
+    /// Wrap a focus or layout event so receivers can drop stale copies
+    fn sequenced(&self, event: NetEvent) -> NetEvent {
+        NetEvent::Sequenced(self.sequence.stamp(), Box::new(event))
+    }
+
+    /// Send an event to every peer, over the multicast group or,
+    /// when discovering peers over mDNS, to each peer directly
+    fn send_to_all(&self, event: &NetEvent) -> io::Result<Option<()>> {
+        if self.discovery.is_none() {
+            return self.net.send_to_all(event);
+        }
+
+        let mut sent = false;
+        let mut last_err = None;
+        for screen in self.cluster.remote_screens() {
+            let addrs = screen.addrs();
+            if let Some(addr) = self.routes.best(&addrs) {
+                match self.net.send_to(event, addr) {
+                    Ok(_) => sent = true,
+                    Err(err) => {
+                        warn!("Failed to send event to {}: {}", addr, err);
+                        last_err = Some(err);
+                    },
+                }
+            }
+        }
+
+        // Succeed if any of the peers could be reached
+        match last_err {
+            Some(err) if !sent => Err(err),
+            _ => Ok(Some(())),
+        }
+    }
+
+    /// Connect to a peer found through mDNS
+    fn peer_discovered(&mut self, peer: Peer) {
+        if peer.version != PROTOCOL_VERSION {
+            warn!("Ignoring peer {} with protocol version {}", peer.screen, peer.version);
+            return;
+        }
+
+        if self.cluster.contains_addr(&peer.addrs) || self.cluster.is_removed(peer.host_id) {
+            return;
+        }
+
+        info!("Discovered peer {} ({:016x})", peer.screen, peer.host_id);
+        match self.routes.best(&peer.addrs) {
+            Some(addr) => if let Err(err) = self.net.send_to(&NetEvent::Connect(self.cluster.layout().clone()), addr) {
+                self.report_error(format!("Failed to connect to {}: {}", addr, err));
+            },
+            None => warn!("Peer {} did not advertise any addresses", peer.screen),
+        }
+    }
+
+    fn route_status(&self) -> Vec<RouteStatus> {
+        self.cluster.get_screens().iter().enumerate()
+            .filter(|&(i, _)| i as Index != self.cluster.local_index())
+            .flat_map(|(i, screen)| {
+                screen.addrs().into_iter()
+                    .map(|addr| self.routes.status(i as Index, &addr))
+                    .collect::<Vec<_>>()
+            })
+            .collect()
     }
 
-    fn broadcast_net_event(&self, event: &NetEvent) {
-        match self.clients {
-            Some(ref clients) => self.send_net_event(event, clients),
-            None => unreachable!("Cannot broadcast without clients"),
+    /// Probe every address of every peer and report the results
+    /// from the last round to the config clients
+    fn probe(&mut self, event_loop: &mut EventLoop<Self>) {
+        let status = self.route_status();
+        self.broadcast_event(Event::Routes(status));
+        if self.peer_status() && self.state == State::Connected {
+            warn!("Lost contact with every peer");
+            self.set_state(event_loop, State::Waiting);
+        }
+
+        self.check_focused_peer(event_loop);
+
+        for (addr, event) in self.routes.probe(&self.cluster.remote_addrs()) {
+            if let Err(err) = self.net.send_to(&event, &addr) {
+                debug!("Failed to probe {}: {}", addr, err);
+            }
+        }
+    }
+
+    /// Reload the config file and screen layout, applying
+    /// the options that can change without a restart
+    fn reload(&mut self) -> io::Result<()> {
+        let config = try!(self.source.load());
+        if config.network != self.config.network ||
+            config.discovery != self.config.discovery ||
+            config.config_server != self.config.config_server ||
+            config.pointer.accelerated != self.config.pointer.accelerated
+        {
+            warn!("Network, discovery, config server and acceleration changes apply after a restart");
+        }
+
+        self.cluster.set_entry(config.entry());
+        self.cluster.set_relative_key(config.hotkeys.relative_toggle);
+        self.host.set_escape_keys(&config.hotkeys.escape);
+        let rules_changed = self.cluster.set_rules(config.rules());
+        self.arbiter.set_rules(config.rules());
+        self.config = config;
+        info!("Reloaded {}", self.source.path.display());
+
+        if self.cluster.reload_screens() || rules_changed {
+            self.cluster.update_barriers(&self.host);
+            self.broadcast_cluster();
+            try!(self.send_to_all(&self.sequenced(NetEvent::Cluster(self.cluster.layout().clone()))));
+        }
+
+        Ok(())
+    }
+
+    fn control_connected(&mut self, event_loop: &mut EventLoop<Self>, client: ControlClient) {
+        let id = self.next_control_client;
+        self.next_control_client += 1;
+        if let Err(err) = event_loop.register(&client,
+                                              Token(CONTROL_CLIENT_EVENTS + id),
+                                              EventSet::readable(),
+                                              PollOpt::level())
+        {
+            error!("Failed to register control connection: {}", err);
+            return;
+        }
+
+        event_loop.timeout_ms(Timer::ControlRequest(id), ctl::REQUEST_TIMEOUT_MS).unwrap();
+        self.control_clients.insert(id, client);
+    }
+
+    /// Answer a control connection once its whole request has arrived
+    fn control_readable(&mut self, event_loop: &mut EventLoop<Self>, id: usize) {
+        let result = match self.control_clients.get_mut(&id) {
+            Some(client) => client.read(),
+            None => return,
+        };
+
+        let response = match result {
+            Ok(Some(request)) => self.control_request(event_loop, request),
+            Ok(None) => return,
+            Err(err) => ctl::Response::Error(format!("{}", err)),
+        };
+
+        if let Some(client) = self.control_clients.get(&id) {
+            if let Err(err) = client.respond(&response) {
+                error!("Failed to reply to control request: {}", err);
+            }
+        }
+
+        self.control_closed(event_loop, id);
+    }
+
+    fn control_closed(&mut self, event_loop: &mut EventLoop<Self>, id: usize) {
+        if let Some(client) = self.control_clients.remove(&id) {
+            let _ = event_loop.deregister(&client);
+        }
+    }
+
+    fn control_request(&mut self, event_loop: &mut EventLoop<Self>, request: ctl::Request) -> ctl::Response {
+        match request {
+            ctl::Request::Status => {
+                let remote = self.cluster.remote_screens();
+                let online = remote.iter()
+                    .filter(|screen| screen.addrs().iter().any(|addr| self.routes.is_alive(addr)))
+                    .count();
+
+                let name = |index| self.cluster.screen(index)
+                    .map_or(String::new(), |screen| screen.name().to_owned());
+
+                ctl::Response::Status(ctl::Status {
+                    state: format!("{:?}", self.state),
+                    screen: name(self.cluster.local_index()),
+                    focus: name(self.cluster.focused_screen_index()),
+                    locked: self.cluster.is_locked(),
+                    relative: self.cluster.is_relative(),
+                    peers: remote.len(),
+                    peers_online: online,
+                    ui: self.ui_url.clone(),
+                })
+            },
+            ctl::Request::Screens => {
+                let local = self.cluster.local_index();
+                let focused = self.cluster.focused_screen_index();
+                ctl::Response::Screens(self.cluster.get_screens().iter().enumerate()
+                    .map(|(i, screen)| ctl::ScreenInfo {
+                        index: i as Index,
+                        name: screen.name().to_owned(),
+                        addrs: screen.addrs().iter().map(|addr| format!("{}", addr)).collect(),
+                        local: i as Index == local,
+                        focused: i as Index == focused,
+                    })
+                    .collect())
+            },
+            ctl::Request::Focus(name) => {
+                let index = match self.cluster.find_screen(&name) {
+                    Some(index) => index,
+                    None => return ctl::Response::Error(format!("No screen named {}", name)),
+                };
+
+                if let Some(controller) = self.arbiter.controller() {
+                    if !self.arbiter.allows(&self.local_claim(true)) {
+                        let name = self.cluster.find_host(controller)
+                            .and_then(|index| self.cluster.screen(index))
+                            .map_or(format!("{:016x}", controller), |screen| screen.name().to_owned());
+                        return ctl::Response::Error(format!("Screen {} controls focus", name));
+                    }
+                }
+
+                match self.cluster.focus_screen(&self.host, index) {
+                    Ok(focus) => {
+                        self.focus_changed(event_loop, focus, true);
+                        ctl::Response::Ok
+                    },
+                    Err(err) => ctl::Response::Error(err),
+                }
+            },
+            ctl::Request::Pointer { screen, scale, accel } => {
+                let index = match self.cluster.find_screen(&screen) {
+                    Some(index) => index,
+                    None => return ctl::Response::Error(format!("No screen named {}", screen)),
+                };
+
+                match self.cluster.set_pointer(index, scale, accel) {
+                    Ok(_) => {
+                        self.layout_changed();
+                        ctl::Response::Ok
+                    },
+                    Err(err) => ctl::Response::Error(err),
+                }
+            },
+            ctl::Request::Lock => {
+                self.cluster.set_locked(&self.host, true);
+                ctl::Response::Ok
+            },
+            ctl::Request::Unlock => {
+                self.cluster.set_locked(&self.host, false);
+                ctl::Response::Ok
+            },
+            ctl::Request::Reload => match self.reload() {
+                Ok(_) => ctl::Response::Ok,
+                Err(err) => ctl::Response::Error(format!("{}", err)),
+            },
+        }
+    }
+
+    /// Tell config clients where focus is, at most every
+    /// `FOCUS_STATUS_INTERVAL_MS` unless the focused screen changed
+    fn focus_status(&mut self, focus: Focus) {
+        let interval = Duration::from_millis(FOCUS_STATUS_INTERVAL_MS);
+        if self.status.focus_index != Some(focus.index()) || self.status.focus_sent.elapsed() >= interval {
+            self.send_focus_status(focus);
+        } else {
+            self.status.pending_focus = Some(focus);
+        }
+    }
+
+    fn send_focus_status(&mut self, focus: Focus) {
+        let (x, y) = focus.pos();
+        self.status.focus_index = Some(focus.index());
+        self.status.focus_sent = Instant::now();
+        self.status.pending_focus = None;
+        self.broadcast_event(Event::Focus(FocusStatus {
+            screen: focus.index(),
+            x: x,
+            y: y,
+        }));
+    }
+
+    /// Tell config clients about peers that came online or went
+    /// offline, returning whether every online peer went offline
+    fn peer_status(&mut self) -> bool {
+        let was_online = self.status.online.values().any(|&alive| alive);
+        let mut events = Vec::new();
+        let mut online = HashMap::new();
+        for (i, screen) in self.cluster.get_screens().iter().enumerate() {
+            if i as Index == self.cluster.local_index() {
+                continue;
+            }
+
+            let alive = screen.addrs().iter().any(|addr| self.routes.is_alive(addr));
+            let was_alive = self.status.online.get(screen.name()).cloned().unwrap_or(false);
+            if alive != was_alive {
+                let peer = PeerStatus { screen: i as Index, name: screen.name().to_owned() };
+                events.push(if alive { Event::PeerOnline(peer) } else { Event::PeerOffline(peer) });
+            }
+
+            online.insert(screen.name().to_owned(), alive);
+        }
+
+        let is_online = online.values().any(|&alive| alive);
+        self.status.online = online;
+        for event in events {
+            self.broadcast_event(event);
+        }
+
+        was_online && !is_online
+    }
+
+    /// Log an error and let config clients know about it
+    fn report_error(&self, msg: String) {
+        error!("{}", msg);
+        self.broadcast_event(Event::Error(msg));
+    }
+
+    /// Handle a config API request
+    fn api_request(&mut self, method: api::Method) -> Reply {
+        let result = match method {
+            api::Method::GetStatus => {
+                let (x, y) = self.cluster.focus().pos();
+                return Reply::Status(api::Status {
+                    state: format!("{:?}", self.state),
+                    focus: FocusStatus {
+                        screen: self.cluster.focused_screen_index(),
+                        x: x,
+                        y: y,
+                    },
+                    locked: self.cluster.is_locked(),
+                    relative: self.cluster.is_relative(),
+                    routes: self.route_status(),
+                });
+            },
+            api::Method::GetCluster => {
+                return Reply::Cluster(ClusterView::new(&self.cluster));
+            },
+            api::Method::RenameScreen { screen, name } => {
+                self.cluster.rename_screen(screen, name)
+            },
+            api::Method::RemoveScreen { screen } => {
+                let host_id = self.cluster.screen(screen).and_then(|screen| screen.host_id());
+                self.cluster.remove_screen(&self.host, screen).map(|_| {
+                    // Screens after the removed one have shifted down, so
+                    // nothing that refers to them by index is valid anymore
+                    self.focus.sent_index = None;
+                    self.focus.pending = None;
+                    self.status.focus_index = None;
+                    if let Some(host_id) = host_id {
+                        self.arbiter.release(host_id);
+                    }
+                })
+            },
+            api::Method::ReadmitScreen { host_id } => {
+                api::parse_host_id(&host_id).and_then(|host_id| self.cluster.readmit_screen(host_id))
+            },
+            api::Method::SetEdge { screen, edge, neighbour } => {
+                api::parse_edge(&edge)
+                    .and_then(|edge| self.cluster.set_edge(screen, edge, neighbour))
+            },
+            api::Method::SetEdgePolicy { screen, edge, policy } => {
+                api::parse_edge(&edge).and_then(|edge| {
+                    api::parse_policy(&policy)
+                        .and_then(|policy| self.cluster.set_edge_policy(screen, edge, policy))
+                })
+            },
+            api::Method::SetPointer { screen, scale, accel } => {
+                self.cluster.set_pointer(screen, scale, accel)
+            },
+        };
+
+        match result {
+            Ok(_) => {
+                self.layout_changed();
+                Reply::Ok
+            },
+            Err(err) => Reply::Error(err),
+        }
+    }
+
+    /// Share an edited layout with the peers and config clients
+    fn layout_changed(&mut self) {
+        self.cluster.update_barriers(&self.host);
+        self.broadcast_cluster();
+        if let Err(err) = self.send_to_all(&self.sequenced(NetEvent::Cluster(self.cluster.layout().clone()))) {
+            self.report_error(format!("Failed to send cluster: {}", err));
+        }
+    }
+
+    fn broadcast_cluster(&self) {
+        self.broadcast_event(Event::Cluster(ClusterView::new(&self.cluster)));
+    }
+
+    fn broadcast_event(&self, event: Event) {
+        if let Some(ref clients) = self.clients {
+            self.send_api(&api::Message::Event(event), clients);
+        }
+    }
+
+    fn send_api(&self, msg: &api::Message, sender: &WsSender) {
+        let msg = serde_json::to_string(msg).unwrap();
+        if let Err(err) = sender.send(msg) {
+            debug!("Failed to send to config clients: {}", err);
         }
     }
 }
@@ -154,8 +1356,8 @@ impl<H, N> Handler for Hub<H, N> where
     H: HostInterface + Evented,
     N: NetInterface + Evented,
 {
-    type Timeout = ();
-    type Message = (NetEvent, WsSender);
+    type Timeout = Timer;
+    type Message = (ClientEvent, WsSender);
 
     fn ready(&mut self,
              event_loop: &mut EventLoop<Self>,
@@ -168,27 +1370,24 @@ impl<H, N> Handler for Hub<H, N> where
                     // many host events, so process all host events
                     // Be careful in host.recv_event so this doesn't infinite loop
                     while let Some(event) = self.host.recv_event() {
-                        self.host_event(event);
+                        self.host_event(event_loop, event);
                     }
                 }
             },
             NET_EVENT => {
                 if events.is_readable() {
                     match self.net.recv_from() {
-                        Ok(Some((event, addr))) => self.net_event(event, &addr),
+                        Ok(Some((event, addr))) => self.net_event(event_loop, event, &addr),
                         Ok(None) => (),
-                        Err(err) => error!("Failed to receive event: {}", err),
+                        Err(err) => self.report_error(format!("Failed to receive event: {}", err)),
                     }
                 }
 
                 if events.is_writable() {
                     match self.state {
                         State::Connecting => {
-                            if let Err(err) = self.net.send_to_all(&NetEvent::Connect(self.cluster.clone())) {
-                                error!("Failed to connect: {}", err);
-                            }
-
-                            self.state = State::Waiting;
+                            self.announce();
+                            self.set_state(event_loop, State::Waiting);
                             event_loop.reregister(&self.net,
                                                   NET_EVENT,
                                                   EventSet::readable(),
@@ -198,20 +1397,134 @@ impl<H, N> Handler for Hub<H, N> where
                     }
                 }
             },
+            DISCOVERY_EVENT => {
+                loop {
+                    let result = match self.discovery {
+                        Some(ref discovery) => discovery.recv(),
+                        None => break,
+                    };
+
+                    match result {
+                        Ok(Some(peers)) => for peer in peers {
+                            self.peer_discovered(peer);
+                        },
+                        Ok(None) => break,
+                        Err(err) => {
+                            error!("Failed to receive discovery event: {}", err);
+                            break;
+                        },
+                    }
+                }
+            },
+            CONTROL_EVENT => {
+                loop {
+                    let result = match self.control {
+                        Some(ref control) => control.accept(),
+                        None => break,
+                    };
+
+                    match result {
+                        Ok(Some(client)) => self.control_connected(event_loop, client),
+                        Ok(None) => break,
+                        Err(err) => {
+                            error!("Failed to accept control connection: {}", err);
+                            break;
+                        },
+                    }
+                }
+            },
+            SIGNAL_EVENT => {
+                loop {
+                    let result = match self.signals {
+                        Some(ref signals) => signals.recv(),
+                        None => break,
+                    };
+
+                    match result {
+                        Ok(Some(signal::SIGHUP)) => if let Err(err) = self.reload() {
+                            self.report_error(format!("Failed to reload config: {}", err));
+                        },
+                        Ok(Some(signal::SIGINT)) | Ok(Some(signal::SIGTERM)) => {
+                            self.shutdown(event_loop);
+                            break;
+                        },
+                        Ok(Some(signum)) => warn!("Unexpected signal {}", signum),
+                        Ok(None) => break,
+                        Err(err) => {
+                            error!("Failed to receive signal: {}", err);
+                            break;
+                        },
+                    }
+                }
+            },
+            NETLINK_EVENT => {
+                let result = match self.netlink {
+                    Some(ref netlink) => netlink.recv(),
+                    None => Ok(false),
+                };
+
+                match result {
+                    Ok(true) => if !self.network_change_scheduled {
+                        event_loop.timeout_ms(Timer::NetworkChange, NETWORK_SETTLE_MS).unwrap();
+                        self.network_change_scheduled = true;
+                    },
+                    Ok(false) => (),
+                    Err(err) => error!("Failed to receive network change: {}", err),
+                }
+            },
+            Token(token) if token >= CONTROL_CLIENT_EVENTS => {
+                self.control_readable(event_loop, token - CONTROL_CLIENT_EVENTS);
+            },
             _ => unreachable!(),
         }
     }
 
+    fn timeout(&mut self, event_loop: &mut EventLoop<Self>, timer: Timer) {
+        match timer {
+            Timer::Probe => {
+                self.probe(event_loop);
+                event_loop.timeout_ms(Timer::Probe, PROBE_INTERVAL_MS).unwrap();
+            },
+            Timer::FlushFocus => {
+                self.flush_focus();
+            },
+            Timer::Ownership => {
+                self.send_ownership();
+                event_loop.timeout_ms(Timer::Ownership, OWNERSHIP_INTERVAL_MS).unwrap();
+            },
+            Timer::GrabCheck => {
+                self.check_pointer_lock();
+                event_loop.timeout_ms(Timer::GrabCheck, GRAB_CHECK_INTERVAL_MS).unwrap();
+            },
+            Timer::GrabRetry => {
+                self.retry_grab(event_loop);
+            },
+            Timer::Announce => {
+                self.reannounce(event_loop);
+            },
+            Timer::NetworkChange => {
+                self.network_changed();
+            },
+            Timer::ControlRequest(id) => {
+                if self.control_clients.contains_key(&id) {
+                    debug!("Control connection sent no request in time");
+                    self.control_closed(event_loop, id);
+                }
+            },
+        }
+    }
+
     fn notify(&mut self, _: &mut EventLoop<Self>, msg: Self::Message) {
-        match msg.0 {
-            NetEvent::RequestCluster => {
-                self.send_net_event(&NetEvent::Cluster(self.cluster.clone()), &msg.1);
+        let (event, client) = msg;
+        match event {
+            ClientEvent::Connected => {
+                let cluster = ClusterView::new(&self.cluster);
+                self.send_api(&api::Message::Event(Event::Cluster(cluster)), &client);
             },
-            NetEvent::Screens(screens) => {
-                self.cluster.set_screens(screens);
-                self.net.send_to_all(&NetEvent::Cluster(self.cluster.clone())).unwrap();
+            ClientEvent::Request(request) => {
+                let result = self.api_request(request.method);
+                self.send_api(&api::Message::Response { id: Some(request.id), result: result }, &client);
             },
-            event => warn!("Unexpected config event: {:?}", event),
         }
     }
 }
@@ -1,20 +1,39 @@
-use cluster::{Cluster, Screen, Focus};
+use cluster::{Layout, Focus};
+use sequence::Stamp;
+use arbiter::Claim;
 
 use std::io;
 use std::net::SocketAddr;
 
+/// Bumped whenever the peer wire protocol changes incompatibly
+pub const PROTOCOL_VERSION: u32 = 10;
+
+/// Forces focus back to the local screen
+pub const ESCAPE_KEYS: [u64; 3] = [0xffe3, 0xffe9, 0xff1b]; // XK_Control_L, XK_Alt_L, XK_Escape
+
 pub trait HostInterface {
     fn screen_size(&self) -> (i32, i32);
+    fn screen_size_mm(&self) -> (i32, i32);
     fn cursor_pos(&self) -> (i32, i32);
-    fn grab_cursor(&self);
+    /// Fails if another client holds a grab
+    fn grab_cursor(&self) -> io::Result<()>;
     fn ungrab_cursor(&self);
-    fn grab_keyboard(&self);
+    fn grab_keyboard(&self) -> io::Result<()>;
     fn ungrab_keyboard(&self);
+    fn set_barriers(&self, barriers: Barriers);
+    /// Whether a local application has locked the pointer in place, as games do
+    fn pointer_locked(&self) -> bool;
+    /// Keysyms that raise `HostEvent::Escape` when held together
+    fn set_escape_keys(&self, keys: &[u64]);
     fn recv_event(&self) -> Option<HostEvent>;
     fn send_event(&self, event: HostEvent);
 }
 
 pub trait NetInterface {
+    fn local_addrs(&self) -> Vec<SocketAddr>;
+    /// Look up the local addresses again after a network change,
+    /// returning whether they changed
+    fn refresh(&mut self) -> io::Result<bool>;
     fn send_to(&self, event: &NetEvent, addr: &SocketAddr) -> io::Result<Option<()>>;
     fn send_to_all(&self, event: &NetEvent) -> io::Result<Option<()>>;
     fn recv_from(&self) -> io::Result<Option<(NetEvent, SocketAddr)>>;
@@ -27,17 +46,33 @@ pub enum HostEvent {
     Button(ButtonEvent),
     Key(KeyEvent),
     Selection(Selection),
+    Barrier(BarrierEvent),
+    /// A key or button was pressed on a real local device
+    Activity,
+    /// The escape hotkey was pressed while the keyboard was grabbed
+    Escape,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub enum NetEvent {
-    Connect(Cluster),
+    Connect(Layout),
     RequestCluster,
-    Cluster(Cluster),
-    Screens(Vec<Screen>),
+    Cluster(Layout),
+    /// Focus moved by local input, sent to peers as a `Claim`
     Focus(Focus),
+    /// A host moved focus and asks to control it
+    Claim(Claim, Focus),
+    PointerGrab(bool),
+    Motion(MotionEvent),
     Button(ButtonEvent),
     Key(KeyEvent),
+    Ping(u64),
+    Pong(u64),
+    /// The sender is shutting down
+    Leave,
+    /// A focus or layout event that should be dropped if it arrives
+    /// after a newer one from the same host
+    Sequenced(Stamp, Box<NetEvent>),
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -52,6 +87,15 @@ pub struct MotionEvent {
     pub dy: i32,
 }
 
+/// The cursor was pushed against a barrier at (x, y)
+#[derive(Serialize, Deserialize, Debug)]
+pub struct BarrierEvent {
+    pub x: i32,
+    pub y: i32,
+    pub dx: i32,
+    pub dy: i32,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct ButtonEvent {
     pub button: u32,
@@ -69,3 +113,13 @@ pub enum Selection {
     Primary,
     Clipboard,
 }
+
+/// Screen edges the cursor should be stopped at and reported
+/// through `HostEvent::Barrier`, instead of moving freely
+#[derive(Clone, Copy, PartialEq, Default, Debug)]
+pub struct Barriers {
+    pub top: bool,
+    pub right: bool,
+    pub bottom: bool,
+    pub left: bool,
+}
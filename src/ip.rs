@@ -1,47 +1,174 @@
 use io::*;
+use util;
+use fragment::{self, Reassembler};
 
 use mio::*;
-use mio::udp::UdpSocket;
+use net2::{UdpBuilder, UdpSocketExt};
+use net2::unix::UnixUdpBuilderExt;
 use bincode::{serde as bincode_serde, SizeLimit};
+use libc;
 
 use std::io;
-use std::net::{SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::cell::{Cell, RefCell};
+use std::net::{UdpSocket, IpAddr, SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::os::unix::io::AsRawFd;
 
 pub struct Config {
     pub server_addr: IpAddr,
-    pub multicast_addr: IpAddr,
-    pub port: u16
+    pub multicast_addrs: Vec<IpAddr>,
+    pub port: u16,
+    pub interfaces: InterfaceFilter,
+}
+
+/// Allow and deny lists of interface names used to pick which
+/// interfaces are joined to the multicast groups and advertised.
+/// Names may end with a `*` wildcard (eg. `docker*`).
+#[derive(Clone, Debug)]
+pub struct InterfaceFilter {
+    pub allow: Vec<String>,
+    pub deny: Vec<String>,
+}
+
+impl InterfaceFilter {
+    pub fn is_allowed(&self, name: &str) -> bool {
+        let matches = |pattern: &String| {
+            if pattern.ends_with('*') {
+                name.starts_with(&pattern[..pattern.len() - 1])
+            } else {
+                name == pattern
+            }
+        };
+
+        (self.allow.is_empty() || self.allow.iter().any(&matches)) &&
+            !self.deny.iter().any(&matches)
+    }
 }
 
 pub struct IpInterface {
-    socket: udp::UdpSocket,
+    socket: UdpSocket,
     config: Config,
+    interfaces: Vec<util::HostAddr>,
+    next_message: Cell<u32>,
+    reassembler: RefCell<Reassembler>,
 }
 
 impl IpInterface {
     pub fn open(config: Config) -> io::Result<Self> {
-        let socket = try!(UdpSocket::v4());
-        try!(socket.set_multicast_loop(false));
-        try!(socket.join_multicast(&config.multicast_addr));
-        try!(socket.bind(&match config.server_addr {
+        Self::bind(config, false)
+    }
+
+    /// Open a socket on a port that may be shared
+    /// with other processes (eg. mDNS responders)
+    pub fn open_shared(config: Config) -> io::Result<Self> {
+        Self::bind(config, true)
+    }
+
+    fn bind(config: Config, shared: bool) -> io::Result<Self> {
+        let builder = match config.server_addr {
+            IpAddr::V4(_) => try!(UdpBuilder::new_v4()),
+            IpAddr::V6(_) => {
+                // Accept IPv4 peers on the same socket (dual-stack)
+                let builder = try!(UdpBuilder::new_v6());
+                try!(builder.only_v6(false));
+                builder
+            },
+        };
+
+        try!(builder.reuse_address(true));
+        if shared {
+            try!(builder.reuse_port(true));
+        }
+
+        let socket = try!(builder.bind(&match config.server_addr {
             IpAddr::V4(addr) => SocketAddr::V4((SocketAddrV4::new(addr, config.port))),
             IpAddr::V6(addr) => SocketAddr::V6((SocketAddrV6::new(addr, config.port, 0, 0))),
         }));
 
-        Ok(IpInterface {
+        try!(socket.set_nonblocking(true));
+        try!(socket.set_multicast_loop_v4(false));
+        if let IpAddr::V6(_) = config.server_addr {
+            try!(socket.set_multicast_loop_v6(false));
+        }
+
+        let interfaces = try!(host_interfaces(&config.interfaces));
+        if interfaces.is_empty() {
+            warn!("No network interfaces matched the interface filter");
+        }
+
+        let net = IpInterface {
             config: config,
             socket: socket,
-        })
+            interfaces: interfaces,
+            next_message: Cell::new(0),
+            reassembler: RefCell::new(Reassembler::new()),
+        };
+
+        net.join_groups();
+        Ok(net)
     }
-}
 
-impl NetInterface for IpInterface {
-    fn send_to(&self, event: &NetEvent, addr: &SocketAddr) -> io::Result<Option<()>> {
-        let packet = bincode_serde::serialize(event, SizeLimit::Bounded(1024)).unwrap();
-        debug!("=> {} <= ({} bytes) {:#?}", addr, packet.len(), event);
-        match self.socket.send_to(&packet, addr) {
-            Ok(Some(_)) => Ok(Some(())),
-            Ok(None) => Err(io::Error::new(
+    /// Join the multicast groups on every chosen interface. A failure
+    /// on one interface doesn't stop the others from being joined.
+    fn join_groups(&self) {
+        for group in &self.config.multicast_addrs {
+            for iface in multicast_interfaces(&self.interfaces, group) {
+                let result = match (*group, iface.ip, self.config.server_addr) {
+                    (IpAddr::V4(group), IpAddr::V4(addr), _) => {
+                        self.socket.join_multicast_v4(&group, &addr)
+                    },
+                    (IpAddr::V6(group), _, IpAddr::V6(_)) => {
+                        self.socket.join_multicast_v6(&group, iface.index)
+                    },
+                    (IpAddr::V6(group), _, IpAddr::V4(_)) => {
+                        warn!("Cannot join IPv6 multicast group {} on an IPv4 socket", group);
+                        break;
+                    },
+                    _ => unreachable!(),
+                };
+
+                match result {
+                    Ok(_) => info!("Joined multicast group {} on {}", group, iface.interface),
+                    // Already joined on this interface
+                    Err(ref err) if err.raw_os_error() == Some(libc::EADDRINUSE) => (),
+                    Err(err) => warn!("Failed to join multicast group {} on {}: {}", group, iface.interface, err),
+                }
+            }
+        }
+    }
+
+    /// Serialize an event into one or more datagrams
+    fn encode(&self, event: &NetEvent) -> io::Result<Vec<Vec<u8>>> {
+        let message = try!(bincode_serde::serialize(event, SizeLimit::Bounded(fragment::MAX_MESSAGE_SIZE as u64))
+            .map_err(|err| io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Failed to serialize event: {}", err)
+            )));
+
+        let id = self.next_message.get();
+        self.next_message.set(id.wrapping_add(1));
+        Ok(fragment::split(id, &message))
+    }
+
+    fn is_dual_stack(&self) -> bool {
+        match self.config.server_addr {
+            IpAddr::V4(_) => false,
+            IpAddr::V6(_) => true,
+        }
+    }
+
+    pub fn send_packet(&self, packet: &[u8], addr: &SocketAddr) -> io::Result<Option<()>> {
+        let addr = match (*addr, self.is_dual_stack()) {
+            (SocketAddr::V4(_), true) => util::to_ipv6_mapped(addr),
+            (SocketAddr::V6(_), false) => return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Cannot send to an IPv6 address from an IPv4 socket"
+            )),
+            (addr, _) => addr,
+        };
+
+        match self.socket.send_to(packet, &addr) {
+            Ok(_) => Ok(Some(())),
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => Err(io::Error::new(
                 io::ErrorKind::WouldBlock,
                 "The OS socket buffer is probably full"
             )),
@@ -49,24 +176,163 @@ impl NetInterface for IpInterface {
         }
     }
 
+    pub fn send_packet_to_all(&self, packet: &[u8]) -> io::Result<Option<()>> {
+        let mut sent = false;
+        let mut last_err = None;
+        for group in &self.config.multicast_addrs {
+            if let (&IpAddr::V6(_), false) = (group, self.is_dual_stack()) {
+                continue;
+            }
+
+            let addr = match *group {
+                IpAddr::V4(addr) => SocketAddr::V4((SocketAddrV4::new(addr, self.config.port))),
+                IpAddr::V6(addr) => SocketAddr::V6((SocketAddrV6::new(addr, self.config.port, 0, 0))),
+            };
+
+            // Send a copy out of every chosen interface
+            for iface in multicast_interfaces(&self.interfaces, group) {
+                let fd = self.socket.as_raw_fd();
+                let result = match iface.ip {
+                    IpAddr::V4(ip) => util::set_multicast_if_v4(fd, &ip),
+                    IpAddr::V6(_) => util::set_multicast_if_v6(fd, iface.index),
+                }.map_err(io::Error::from).and_then(|_| self.send_packet(packet, &addr));
+
+                match result {
+                    Ok(_) => sent = true,
+                    Err(err) => {
+                        warn!("Failed to send packet to {} on {}: {}", addr, iface.interface, err);
+                        last_err = Some(err);
+                    },
+                }
+            }
+        }
+
+        // Succeed if any of the multicast groups could be reached
+        match last_err {
+            Some(err) if !sent => Err(err),
+            _ => Ok(Some(())),
+        }
+    }
+
+    pub fn recv_packet(&self, buf: &mut [u8]) -> io::Result<Option<(usize, SocketAddr)>> {
+        match self.socket.recv_from(buf) {
+            Ok((len, addr)) => Ok(Some((len, util::from_ipv6_mapped(&addr)))),
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+}
+
+impl NetInterface for IpInterface {
+    fn local_addrs(&self) -> Vec<SocketAddr> {
+        let port = self.config.port;
+        let dual_stack = self.is_dual_stack();
+        self.interfaces.iter()
+            .filter_map(|addr| match addr.ip {
+                IpAddr::V4(ip) => Some(SocketAddr::V4(SocketAddrV4::new(ip, port))),
+                IpAddr::V6(ip) =>
+                    if dual_stack {
+                        let scope_id = if util::is_link_local_v6(&ip) { addr.scope_id } else { 0 };
+                        Some(SocketAddr::V6(SocketAddrV6::new(ip, port, 0, scope_id)))
+                    } else { None },
+            })
+            .collect()
+    }
+
+    fn refresh(&mut self) -> io::Result<bool> {
+        let interfaces = try!(host_interfaces(&self.config.interfaces));
+        let key = |addrs: &[util::HostAddr]| addrs.iter()
+            .map(|addr| (addr.index, addr.ip))
+            .collect::<Vec<_>>();
+
+        if key(&interfaces) == key(&self.interfaces) {
+            return Ok(false);
+        }
+
+        if interfaces.is_empty() {
+            warn!("No network interfaces matched the interface filter");
+        }
+
+        self.interfaces = interfaces;
+        self.join_groups();
+        Ok(true)
+    }
+
+    fn send_to(&self, event: &NetEvent, addr: &SocketAddr) -> io::Result<Option<()>> {
+        let packets = try!(self.encode(event));
+        debug!("=> {} <= ({} packets) {:#?}", addr, packets.len(), event);
+        for packet in &packets {
+            try!(self.send_packet(packet, addr));
+        }
+
+        Ok(Some(()))
+    }
+
     fn send_to_all(&self, event: &NetEvent) -> io::Result<Option<()>> {
-        let addr = match self.config.multicast_addr {
-            IpAddr::V4(addr) => SocketAddr::V4((SocketAddrV4::new(addr, self.config.port))),
-            IpAddr::V6(addr) => SocketAddr::V6((SocketAddrV6::new(addr, self.config.port, 0, 0))),
-        };
-        
-        self.send_to(event, &addr)
+        let packets = try!(self.encode(event));
+        debug!("=> * <= ({} packets) {:#?}", packets.len(), event);
+        for packet in &packets {
+            try!(self.send_packet_to_all(packet));
+        }
+
+        Ok(Some(()))
     }
 
     fn recv_from(&self) -> io::Result<Option<(NetEvent, SocketAddr)>> {
-        let mut buf = [0; 1024];
-        self.socket.recv_from(&mut buf).map(|result| {
-            result.map(|(len, addr)| {
-                let event = bincode_serde::deserialize::<NetEvent>(&buf[..len]).unwrap();
-                debug!("<= {} => ({} bytes) {:#?}", addr, len, event);
-                (event, addr)
-            })
-        })
+        let mut buf = [0; fragment::MAX_PACKET_SIZE];
+        let (len, addr) = match try!(self.recv_packet(&mut buf)) {
+            Some(result) => result,
+            None => return Ok(None),
+        };
+
+        let message = match self.reassembler.borrow_mut().push(&addr, &buf[..len]) {
+            Some(message) => message,
+            None => return Ok(None),
+        };
+
+        match bincode_serde::deserialize::<NetEvent>(&message) {
+            Ok(event) => {
+                debug!("<= {} => ({} bytes) {:#?}", addr, message.len(), event);
+                Ok(Some((event, addr)))
+            },
+            Err(err) => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Failed to deserialize event from {}: {}", addr, err)
+            )),
+        }
+    }
+}
+
+/// The host's addresses that pass the interface filter
+fn host_interfaces(filter: &InterfaceFilter) -> io::Result<Vec<util::HostAddr>> {
+    Ok(try!(util::get_host_addrs()).into_iter()
+        .filter(|addr| !is_loopback(&addr.ip) && filter.is_allowed(&addr.interface))
+        .collect())
+}
+
+/// Pick one address per interface that can be used
+/// to join or send to the multicast group
+fn multicast_interfaces<'a>(interfaces: &'a [util::HostAddr], group: &IpAddr) -> Vec<&'a util::HostAddr> {
+    let mut result: Vec<&util::HostAddr> = Vec::new();
+    for iface in interfaces {
+        let same_family = match (*group, iface.ip) {
+            (IpAddr::V4(_), IpAddr::V4(_)) => true,
+            (IpAddr::V6(_), IpAddr::V6(_)) => true,
+            _ => false,
+        };
+
+        if same_family && !result.iter().any(|other| other.index == iface.index) {
+            result.push(iface);
+        }
+    }
+
+    result
+}
+
+fn is_loopback(ip: &IpAddr) -> bool {
+    match *ip {
+        IpAddr::V4(ip) => ip.is_loopback(),
+        IpAddr::V6(ip) => ip.is_loopback(),
     }
 }
 
@@ -76,14 +342,14 @@ impl NetInterface for IpInterface {
  */
 impl Evented for IpInterface {
     fn register(&self, selector: &mut Selector, token: Token, interest: EventSet, opts: PollOpt) -> io::Result<()> {
-        self.socket.register(selector, token, interest, opts)
+        selector.register(self.socket.as_raw_fd(), token, interest, opts)
     }
 
     fn reregister(&self, selector: &mut Selector, token: Token, interest: EventSet, opts: PollOpt) -> io::Result<()> {
-        self.socket.reregister(selector, token, interest, opts)
+        selector.reregister(self.socket.as_raw_fd(), token, interest, opts)
     }
 
     fn deregister(&self, selector: &mut Selector) -> io::Result<()> {
-        self.socket.deregister(selector)
+        selector.deregister(self.socket.as_raw_fd())
     }
 }
@@ -4,6 +4,7 @@
 // Used for util
 extern crate libc;
 extern crate nix;
+extern crate net2;
 
 extern crate mio;
 extern crate ws;
@@ -24,27 +25,64 @@ mod cluster;
 mod io;
 mod x11;
 mod ip;
+mod fragment;
+mod mdns;
+mod route;
+mod ctl;
+mod config;
+mod signal;
+mod netlink;
+mod sequence;
+mod arbiter;
+mod api;
 mod util;
 
 use hub::Hub;
 use mio::IpAddr;
 
-use ip::{IpInterface, Config};
+use ip::{IpInterface, InterfaceFilter};
 use x11::X11Interface;
+use mdns::{MdnsDiscovery, Service};
+use io::NetInterface;
+
+use std::{fmt, process};
+use std::io::Write;
+use std::path::PathBuf;
 
 docopt!(Args derive Debug, "
 Usage:
-  elemeld [-b <bind_addr>] [-m <multicast_addr>] [-p <port>]
+  elemeld [-c <config>] [-b <bind_addr>] [-m <multicast_addr>] [-p <port>] [-i <interfaces>] [-x <interfaces>] [-d <discovery>] [-r <focus_rate>] [-e <entry>] [-u] [--print-config]
+  elemeld ctl status
+  elemeld ctl screens
+  elemeld ctl focus <screen>
+  elemeld ctl pointer <screen> [--scale <scale>] [--accel <accel>]
+  elemeld ctl lock
+  elemeld ctl unlock
+  elemeld ctl reload
   elemeld -h | --help
   elemeld --version
 
+Options override the config file, which is reloaded on SIGHUP.
+
 Options:
-  -b <bind_addr>       Bind address [default: 0.0.0.0].
-  -m <multicast_addr>  Multicast address [default: 224.0.2.42].
-  -p <port>            Port [default: 24242].
+  -c <config>          Config file (~/.config/elemeld/config.json).
+  -b <bind_addr>       Bind address, :: for dual-stack IPv4/IPv6 (::).
+  -m <multicast_addr>  Comma separated multicast addresses (224.0.2.42,ff02::242:42).
+  -p <port>            Port (24242).
+  -i <interfaces>      Comma separated interfaces to use, * matches any suffix (*).
+  -x <interfaces>      Comma separated interfaces to ignore (docker*,br-*,veth*,virbr*,vnet*,tun*,tap*,wg*).
+  -d <discovery>       Peer discovery, multicast or mdns (multicast).
+  -r <focus_rate>      Maximum focus updates sent per second (125).
+  -e <entry>           Cursor entry on a screen, edge or restore (edge).
+  -u                   Use unaccelerated pointer motion.
+  --scale <scale>      Pointer sensitivity on the screen (1.0).
+  --accel <accel>      Pointer acceleration exponent on the screen, 1.0 is linear (1.0).
+  --print-config       Print the effective config and exit.
   -h --help            Show this screen.
   -v --version         Show version.
-", flag_p: u16);
+", flag_c: Option<String>, flag_b: Option<String>, flag_m: Option<String>, flag_p: Option<u16>,
+   flag_i: Option<String>, flag_x: Option<String>, flag_d: Option<String>, flag_r: Option<u32>,
+   flag_e: Option<String>, flag_scale: Option<f64>, flag_accel: Option<f64>);
 
 fn main() {
     env_logger::init().unwrap();
@@ -55,13 +93,143 @@ fn main() {
         return;
     };
 
-    let host = X11Interface::open();
-    let net = IpInterface::open(Config {
-        server_addr: args.flag_b.parse::<IpAddr>().unwrap(),
-        multicast_addr: args.flag_m.parse::<IpAddr>().unwrap(),
+    if args.cmd_ctl {
+        return control(&args);
+    }
+
+    let source = config::Source::new(args.flag_c.as_ref().map(PathBuf::from), config::Overrides {
+        bind_addr: args.flag_b.clone(),
+        multicast_addrs: args.flag_m.as_ref().map(|list| split_list(list)),
         port: args.flag_p,
-    }).unwrap();
+        interfaces: args.flag_i.as_ref().map(|list| split_list(list)),
+        ignore_interfaces: args.flag_x.as_ref().map(|list| split_list(list)),
+        discovery: args.flag_d.clone(),
+        focus_rate: args.flag_r,
+        entry: args.flag_e.clone(),
+        accelerated: if args.flag_u { Some(false) } else { None },
+    }).unwrap_or_else(|err| fail(format!("Failed to find the config file: {}", err)));
+
+    let config = match source.load() {
+        Ok(config) => config,
+        Err(err) => fail(err),
+    };
+
+    if args.flag_print_config {
+        println!("{}", config.to_json());
+        return;
+    }
+
+    let server_addr = match config.network.bind_addr.parse::<IpAddr>() {
+        Ok(addr) => addr,
+        Err(err) => fail(format!("Invalid bind address {}: {}", config.network.bind_addr, err)),
+    };
+
+    let mut multicast_addrs = Vec::new();
+    for addr in &config.network.multicast_addrs {
+        match addr.parse::<IpAddr>() {
+            Ok(addr) => multicast_addrs.push(addr),
+            Err(err) => fail(format!("Invalid multicast address {}: {}", addr, err)),
+        }
+    }
+
+    let interfaces = InterfaceFilter {
+        allow: config.network.interfaces.iter()
+            .filter(|name| *name != "*")
+            .cloned()
+            .collect(),
+        deny: config.network.ignore_interfaces.clone(),
+    };
+
+    let host = X11Interface::open(config.pointer.accelerated);
+    let net = IpInterface::open(ip::Config {
+        server_addr: server_addr,
+        multicast_addrs: multicast_addrs,
+        port: config.network.port,
+        interfaces: interfaces.clone(),
+    }).unwrap_or_else(|err| fail(format!("Failed to open the network interface: {}", err)));
+
+    let discovery = match &config.discovery[..] {
+        "mdns" => {
+            let host_id = util::host_id()
+                .unwrap_or_else(|err| fail(format!("Failed to read the host id: {}", err)));
+            let name = util::get_host_name()
+                .unwrap_or_else(|err| fail(format!("Failed to get the host name: {}", err)));
+            let discovery = MdnsDiscovery::open(server_addr, interfaces, Service {
+                host_id: host_id,
+                version: io::PROTOCOL_VERSION,
+                screen: name,
+                port: config.network.port,
+                addrs: net.local_addrs().iter().map(|addr| addr.ip()).collect(),
+            });
+            Some(discovery.unwrap_or_else(|err| fail(format!("Failed to start mDNS discovery: {}", err))))
+        },
+        _ => None,
+    };
+
+    let mut elemeld = Hub::new(host, net, discovery, source, config)
+        .unwrap_or_else(|err| fail(format!("Failed to start: {}", err)));
+    if let Err(err) = elemeld.run() {
+        fail(err);
+    }
+}
+
+fn split_list(list: &str) -> Vec<String> {
+    list.split(',')
+        .map(|item| item.trim())
+        .filter(|item| !item.is_empty() && *item != "*")
+        .map(|item| item.to_owned())
+        .collect()
+}
+
+/// Send a request to the running daemon over the control socket
+fn control(args: &Args) {
+    let request = if args.cmd_status {
+        ctl::Request::Status
+    } else if args.cmd_screens {
+        ctl::Request::Screens
+    } else if args.cmd_focus {
+        ctl::Request::Focus(args.arg_screen.clone())
+    } else if args.cmd_pointer {
+        ctl::Request::Pointer {
+            screen: args.arg_screen.clone(),
+            scale: args.flag_scale,
+            accel: args.flag_accel,
+        }
+    } else if args.cmd_lock {
+        ctl::Request::Lock
+    } else if args.cmd_unlock {
+        ctl::Request::Unlock
+    } else {
+        ctl::Request::Reload
+    };
+
+    let response = match ctl::request(&ctl::socket_path(), &request) {
+        Ok(response) => response,
+        Err(err) => fail(format!("Failed to contact elemeld: {}", err)),
+    };
+
+    match response {
+        ctl::Response::Ok => (),
+        ctl::Response::Status(status) => {
+            println!("State:  {}", status.state);
+            println!("Screen: {}", status.screen);
+            println!("Focus:  {}{}", status.focus, if status.locked { " (locked)" } else { "" });
+            println!("Motion: {}", if status.relative { "relative" } else { "absolute" });
+            println!("Peers:  {}/{} online", status.peers_online, status.peers);
+            println!("UI:     {}", status.ui);
+        },
+        ctl::Response::Screens(screens) => for screen in screens {
+            println!("{}{} {} {}{}",
+                     if screen.focused { '*' } else { ' ' },
+                     screen.index, screen.name, screen.addrs.join(" "),
+                     if screen.local { " (local)" } else { "" });
+        },
+        ctl::Response::Error(err) => fail(err),
+    }
+}
 
-    let mut elemeld = Hub::new(host, net).unwrap();
-    elemeld.run().unwrap();
+/// Report an error on stderr and exit
+fn fail<T: fmt::Display>(msg: T) -> ! {
+    let _ = writeln!(std::io::stderr(), "{}", msg);
+    process::exit(1);
 }
@@ -0,0 +1,637 @@
+use ip::{IpInterface, Config, InterfaceFilter};
+use io::NetInterface;
+use util;
+
+use mio::*;
+
+use std::{io, cmp};
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+
+macro_rules! try_opt {
+    ($expr:expr) => (match $expr {
+        Some(val) => val,
+        None => return None,
+    })
+}
+
+const MDNS_PORT: u16 = 5353;
+const SERVICE: &'static str = "_elemeld._udp.local";
+const TTL: u32 = 120;
+
+const TYPE_A: u16 = 1;
+const TYPE_PTR: u16 = 12;
+const TYPE_TXT: u16 = 16;
+const TYPE_AAAA: u16 = 28;
+const TYPE_SRV: u16 = 33;
+const TYPE_ANY: u16 = 255;
+
+const CLASS_IN: u16 = 1;
+const CACHE_FLUSH: u16 = 0x8000;
+
+/// What we advertise about ourselves over DNS-SD
+pub struct Service {
+    pub host_id: u64,
+    pub version: u32,
+    pub screen: String,
+    pub port: u16,
+    pub addrs: Vec<IpAddr>,
+}
+
+/// A peer found by browsing for elemeld services
+#[derive(Debug)]
+pub struct Peer {
+    pub host_id: u64,
+    pub version: u32,
+    pub screen: String,
+    pub addrs: Vec<SocketAddr>,
+}
+
+/// DNS-SD service discovery over multicast DNS (RFC 6762/6763)
+pub struct MdnsDiscovery {
+    socket: IpInterface,
+    service: Service,
+}
+
+impl MdnsDiscovery {
+    pub fn open(server_addr: IpAddr, interfaces: InterfaceFilter, service: Service) -> io::Result<Self> {
+        let mut multicast_addrs = vec![IpAddr::V4(Ipv4Addr::new(224, 0, 0, 251))];
+        if let IpAddr::V6(_) = server_addr {
+            multicast_addrs.push(IpAddr::V6(Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 0xfb)));
+        }
+
+        let socket = try!(IpInterface::open_shared(Config {
+            server_addr: server_addr,
+            multicast_addrs: multicast_addrs,
+            port: MDNS_PORT,
+            interfaces: interfaces,
+        }));
+
+        Ok(MdnsDiscovery {
+            socket: socket,
+            service: service,
+        })
+    }
+
+    fn instance(&self) -> String {
+        format!("{}-{:016x}.{}", self.service.screen.replace(".", "-"), self.service.host_id, SERVICE)
+    }
+
+    /// A host name of our own, so the address records never clash
+    /// with the ones the system's responder publishes for the host
+    fn target(&self) -> String {
+        format!("{}-{:016x}.local", self.service.screen.replace(".", "-"), self.service.host_id)
+    }
+
+    /// Advertise new addresses after a network change,
+    /// rejoining the mDNS group on any new interface
+    pub fn refresh(&mut self, addrs: Vec<IpAddr>) -> io::Result<()> {
+        try!(self.socket.refresh());
+        self.service.addrs = addrs;
+        Ok(())
+    }
+
+    /// Multicast our service records
+    pub fn announce(&self) -> io::Result<Option<()>> {
+        let instance = self.instance();
+        let target = self.target();
+
+        let mut packet = Packet::new(0x8400);
+        packet.ptr(SERVICE, &instance);
+        packet.srv(&instance, self.service.port, &target);
+        packet.txt(&instance, &[
+            format!("id={:016x}", self.service.host_id),
+            format!("v={}", self.service.version),
+            format!("screen={}", self.service.screen),
+        ]);
+
+        for addr in &self.service.addrs {
+            packet.addr(&target, addr);
+        }
+
+        self.socket.send_packet_to_all(&packet.finish())
+    }
+
+    /// Multicast a query for other elemeld services
+    pub fn browse(&self) -> io::Result<Option<()>> {
+        let mut packet = Packet::new(0);
+        packet.question(SERVICE, TYPE_PTR);
+        self.socket.send_packet_to_all(&packet.finish())
+    }
+
+    /// Receive a single mDNS packet, answering queries for our service
+    /// and returning any elemeld peer described by a response
+    pub fn recv(&self) -> io::Result<Option<Vec<Peer>>> {
+        let mut buf = [0; 9000];
+        let (len, from) = match try!(self.socket.recv_packet(&mut buf)) {
+            Some(result) => result,
+            None => return Ok(None),
+        };
+
+        let message = match Message::parse(&buf[..len]) {
+            Some(message) => message,
+            None => {
+                debug!("Ignoring malformed mDNS packet from {}", from);
+                return Ok(Some(Vec::new()));
+            },
+        };
+
+        if message.flags & 0x8000 == 0 {
+            // Query
+            let asked = message.questions.iter().any(|&(ref name, qtype)| {
+                name_eq(name, SERVICE) && (qtype == TYPE_PTR || qtype == TYPE_ANY)
+            });
+
+            if asked {
+                try!(self.announce());
+            }
+
+            return Ok(Some(Vec::new()));
+        }
+
+        Ok(Some(self.peers(&message, &from)))
+    }
+
+    fn peers(&self, message: &Message, from: &SocketAddr) -> Vec<Peer> {
+        let mut srvs = HashMap::new();
+        let mut txts = HashMap::new();
+        let mut hosts: HashMap<String, Vec<IpAddr>> = HashMap::new();
+        let mut instances = Vec::new();
+
+        for record in &message.records {
+            let name = record.name.to_lowercase();
+            match record.data {
+                RecordData::Ptr(ref instance) => if name_eq(&name, SERVICE) {
+                    instances.push(instance.to_lowercase());
+                },
+                RecordData::Srv(port, ref target) => {
+                    srvs.insert(name, (port, target.to_lowercase()));
+                },
+                RecordData::Txt(ref entries) => {
+                    txts.insert(name, entries.clone());
+                },
+                RecordData::Addr(addr) => {
+                    hosts.entry(name).or_insert_with(Vec::new).push(addr);
+                },
+                RecordData::Other => (),
+            }
+        }
+
+        // Link-local addresses are reachable through the interface
+        // the response arrived on
+        let scope_id = match *from {
+            SocketAddr::V6(addr) => addr.scope_id(),
+            _ => 0,
+        };
+
+        instances.into_iter().filter_map(|instance| {
+            let &(port, ref target) = match srvs.get(&instance) {
+                Some(srv) => srv,
+                None => return None,
+            };
+
+            let txt = match txts.get(&instance) {
+                Some(txt) => txt,
+                None => return None,
+            };
+
+            let value = |key: &str| txt.iter()
+                .filter_map(|entry| {
+                    let mut parts = entry.splitn(2, '=');
+                    match (parts.next(), parts.next()) {
+                        (Some(k), Some(v)) if k == key => Some(v.to_owned()),
+                        _ => None,
+                    }
+                })
+                .next();
+
+            let host_id = match value("id").and_then(|id| u64::from_str_radix(&id, 16).ok()) {
+                Some(id) => id,
+                None => return None,
+            };
+
+            if host_id == self.service.host_id {
+                return None;
+            }
+
+            let addrs = hosts.get(target).map(|addrs| {
+                addrs.iter().map(|addr| match *addr {
+                    IpAddr::V4(ip) => SocketAddr::V4(SocketAddrV4::new(ip, port)),
+                    IpAddr::V6(ip) => {
+                        let scope_id = if util::is_link_local_v6(&ip) { scope_id } else { 0 };
+                        SocketAddr::V6(SocketAddrV6::new(ip, port, 0, scope_id))
+                    },
+                }).collect()
+            }).unwrap_or(Vec::new());
+
+            Some(Peer {
+                host_id: host_id,
+                version: value("v").and_then(|v| v.parse().ok()).unwrap_or(0),
+                screen: value("screen").unwrap_or(String::new()),
+                addrs: addrs,
+            })
+        }).collect()
+    }
+}
+
+fn name_eq(a: &str, b: &str) -> bool {
+    a.trim_right_matches('.').to_lowercase() == b.trim_right_matches('.').to_lowercase()
+}
+
+/// Minimal DNS message writer (no name compression)
+struct Packet {
+    header: [u8; 12],
+    questions: u16,
+    answers: u16,
+    body: Vec<u8>,
+}
+
+impl Packet {
+    fn new(flags: u16) -> Self {
+        let mut header = [0; 12];
+        header[2] = (flags >> 8) as u8;
+        header[3] = flags as u8;
+        Packet { header: header, questions: 0, answers: 0, body: Vec::new() }
+    }
+
+    fn name(&mut self, name: &str) {
+        for label in name.trim_right_matches('.').split('.') {
+            let label = &label.as_bytes()[..cmp::min(label.len(), 63)];
+            self.body.push(label.len() as u8);
+            self.body.extend_from_slice(label);
+        }
+        self.body.push(0);
+    }
+
+    fn u16(&mut self, val: u16) {
+        self.body.push((val >> 8) as u8);
+        self.body.push(val as u8);
+    }
+
+    fn u32(&mut self, val: u32) {
+        self.u16((val >> 16) as u16);
+        self.u16(val as u16);
+    }
+
+    fn question(&mut self, name: &str, qtype: u16) {
+        self.name(name);
+        self.u16(qtype);
+        self.u16(CLASS_IN);
+        self.questions += 1;
+    }
+
+    fn record<F>(&mut self, name: &str, rtype: u16, class: u16, data: F)
+        where F: FnOnce(&mut Self)
+    {
+        self.name(name);
+        self.u16(rtype);
+        self.u16(class);
+        self.u32(TTL);
+
+        // Placeholder for the data length
+        let start = self.body.len();
+        self.u16(0);
+        data(self);
+
+        let len = self.body.len() - start - 2;
+        self.body[start] = (len >> 8) as u8;
+        self.body[start + 1] = len as u8;
+        self.answers += 1;
+    }
+
+    fn ptr(&mut self, name: &str, instance: &str) {
+        self.record(name, TYPE_PTR, CLASS_IN, |p| p.name(instance));
+    }
+
+    fn srv(&mut self, instance: &str, port: u16, target: &str) {
+        self.record(instance, TYPE_SRV, CLASS_IN | CACHE_FLUSH, |p| {
+            p.u16(0); // Priority
+            p.u16(0); // Weight
+            p.u16(port);
+            p.name(target);
+        });
+    }
+
+    fn txt(&mut self, instance: &str, entries: &[String]) {
+        self.record(instance, TYPE_TXT, CLASS_IN | CACHE_FLUSH, |p| {
+            for entry in entries {
+                let entry = &entry.as_bytes()[..cmp::min(entry.len(), 255)];
+                p.body.push(entry.len() as u8);
+                p.body.extend_from_slice(entry);
+            }
+        });
+    }
+
+    fn addr(&mut self, target: &str, addr: &IpAddr) {
+        match *addr {
+            IpAddr::V4(ip) => self.record(target, TYPE_A, CLASS_IN | CACHE_FLUSH, |p| {
+                p.body.extend_from_slice(&ip.octets());
+            }),
+            IpAddr::V6(ip) => self.record(target, TYPE_AAAA, CLASS_IN | CACHE_FLUSH, |p| {
+                for segment in &ip.segments() {
+                    p.u16(*segment);
+                }
+            }),
+        }
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        self.header[4] = (self.questions >> 8) as u8;
+        self.header[5] = self.questions as u8;
+        self.header[6] = (self.answers >> 8) as u8;
+        self.header[7] = self.answers as u8;
+
+        let mut packet = self.header.to_vec();
+        packet.extend_from_slice(&self.body);
+        packet
+    }
+}
+
+/// The parts of a DNS message we care about
+struct Message {
+    flags: u16,
+    questions: Vec<(String, u16)>,
+    records: Vec<Record>,
+}
+
+struct Record {
+    name: String,
+    data: RecordData,
+}
+
+enum RecordData {
+    Ptr(String),
+    Srv(u16, String),
+    Txt(Vec<String>),
+    Addr(IpAddr),
+    Other,
+}
+
+impl Message {
+    fn parse(buf: &[u8]) -> Option<Self> {
+        let mut reader = Reader { buf: buf, pos: 0 };
+        let _id = try_opt!(reader.u16());
+        let flags = try_opt!(reader.u16());
+        let qdcount = try_opt!(reader.u16());
+        let ancount = try_opt!(reader.u16());
+        let nscount = try_opt!(reader.u16());
+        let arcount = try_opt!(reader.u16());
+
+        let mut questions = Vec::new();
+        for _ in 0..qdcount {
+            let name = try_opt!(reader.name());
+            let qtype = try_opt!(reader.u16());
+            let _class = try_opt!(reader.u16());
+            questions.push((name, qtype));
+        }
+
+        let mut records = Vec::new();
+        for _ in 0..(ancount as u32 + nscount as u32 + arcount as u32) {
+            records.push(try_opt!(reader.record()));
+        }
+
+        Some(Message {
+            flags: flags,
+            questions: questions,
+            records: records,
+        })
+    }
+}
+
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn u8(&mut self) -> Option<u8> {
+        let val = self.buf.get(self.pos).cloned();
+        self.pos += 1;
+        val
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        let hi = try_opt!(self.u8()) as u16;
+        let lo = try_opt!(self.u8()) as u16;
+        Some(hi << 8 | lo)
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        let hi = try_opt!(self.u16()) as u32;
+        let lo = try_opt!(self.u16()) as u32;
+        Some(hi << 16 | lo)
+    }
+
+    fn bytes(&mut self, len: usize) -> Option<&'a [u8]> {
+        if self.pos + len > self.buf.len() {
+            return None;
+        }
+
+        let bytes = &self.buf[self.pos..self.pos + len];
+        self.pos += len;
+        Some(bytes)
+    }
+
+    /// Read a possibly compressed domain name
+    fn name(&mut self) -> Option<String> {
+        let mut labels = Vec::new();
+        let mut pos = self.pos;
+        let mut end = None;
+
+        // Bound the number of pointers followed to avoid loops
+        for _ in 0..128 {
+            let len = *try_opt!(self.buf.get(pos)) as usize;
+            if len == 0 {
+                self.pos = end.unwrap_or(pos + 1);
+                return Some(labels.join("."));
+            } else if len & 0xc0 == 0xc0 {
+                let lo = *try_opt!(self.buf.get(pos + 1)) as usize;
+                if end.is_none() {
+                    end = Some(pos + 2);
+                }
+                pos = (len & 0x3f) << 8 | lo;
+            } else {
+                if pos + 1 + len > self.buf.len() {
+                    return None;
+                }
+                labels.push(String::from_utf8_lossy(&self.buf[pos + 1..pos + 1 + len]).into_owned());
+                pos += 1 + len;
+            }
+        }
+
+        None
+    }
+
+    fn record(&mut self) -> Option<Record> {
+        let name = try_opt!(self.name());
+        let rtype = try_opt!(self.u16());
+        let _class = try_opt!(self.u16());
+        let _ttl = try_opt!(self.u32());
+        let len = try_opt!(self.u16()) as usize;
+        let end = self.pos + len;
+
+        let data = match rtype {
+            TYPE_PTR => RecordData::Ptr(try_opt!(self.name())),
+            TYPE_SRV => {
+                let _priority = try_opt!(self.u16());
+                let _weight = try_opt!(self.u16());
+                let port = try_opt!(self.u16());
+                RecordData::Srv(port, try_opt!(self.name()))
+            },
+            TYPE_TXT => {
+                let mut entries = Vec::new();
+                while self.pos < end {
+                    let len = try_opt!(self.u8()) as usize;
+                    let entry = try_opt!(self.bytes(len));
+                    entries.push(String::from_utf8_lossy(entry).into_owned());
+                }
+                RecordData::Txt(entries)
+            },
+            TYPE_A if len == 4 => {
+                let b = try_opt!(self.bytes(4));
+                RecordData::Addr(IpAddr::V4(Ipv4Addr::new(b[0], b[1], b[2], b[3])))
+            },
+            TYPE_AAAA if len == 16 => {
+                let mut s = [0u16; 8];
+                for segment in s.iter_mut() {
+                    *segment = try_opt!(self.u16());
+                }
+                RecordData::Addr(IpAddr::V6(Ipv6Addr::new(s[0], s[1], s[2], s[3], s[4], s[5], s[6], s[7])))
+            },
+            _ => RecordData::Other,
+        };
+
+        self.pos = end;
+        Some(Record { name: name, data: data })
+    }
+}
+
+/*
+ * FIXME(Future):
+ * Method delegation: https://github.com/rust-lang/rfcs/pull/1406
+ */
+impl Evented for MdnsDiscovery {
+    fn register(&self, selector: &mut Selector, token: Token, interest: EventSet, opts: PollOpt) -> io::Result<()> {
+        self.socket.register(selector, token, interest, opts)
+    }
+
+    fn reregister(&self, selector: &mut Selector, token: Token, interest: EventSet, opts: PollOpt) -> io::Result<()> {
+        self.socket.reregister(selector, token, interest, opts)
+    }
+
+    fn deregister(&self, selector: &mut Selector) -> io::Result<()> {
+        self.socket.deregister(selector)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Message, Packet, RecordData, TYPE_PTR, TYPE_SRV, TYPE_A, CLASS_IN};
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+    fn header(qdcount: u16, ancount: u16) -> Vec<u8> {
+        vec![0, 0, 0x84, 0, 0, qdcount as u8, 0, ancount as u8, 0, 0, 0, 0]
+    }
+
+    #[test]
+    fn parses_what_it_writes() {
+        let mut packet = Packet::new(0x8400);
+        packet.question("_elemeld._udp.local", TYPE_PTR);
+        packet.ptr("_elemeld._udp.local", "a-1._elemeld._udp.local");
+        packet.srv("a-1._elemeld._udp.local", 24242, "a-1.local");
+        packet.txt("a-1._elemeld._udp.local", &["id=1".to_owned(), "v=2".to_owned()]);
+        packet.addr("a-1.local", &IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)));
+        packet.addr("a-1.local", &IpAddr::V6(Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 1)));
+
+        let message = Message::parse(&packet.finish()).unwrap();
+        assert_eq!(message.flags, 0x8400);
+        assert_eq!(message.questions, vec![("_elemeld._udp.local".to_owned(), TYPE_PTR)]);
+        assert_eq!(message.records.len(), 5);
+
+        match message.records[1].data {
+            RecordData::Srv(port, ref target) => {
+                assert_eq!(port, 24242);
+                assert_eq!(target, "a-1.local");
+            },
+            _ => panic!("Expected an SRV record"),
+        }
+
+        match message.records[2].data {
+            RecordData::Txt(ref entries) => assert_eq!(entries, &["id=1", "v=2"]),
+            _ => panic!("Expected a TXT record"),
+        }
+
+        match message.records[4].data {
+            RecordData::Addr(addr) => assert_eq!(addr, IpAddr::V6(Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 1))),
+            _ => panic!("Expected an AAAA record"),
+        }
+    }
+
+    #[test]
+    fn follows_compressed_names() {
+        let mut packet = header(0, 2);
+        packet.extend_from_slice(&[8, b'_', b'e', b'l', b'e', b'm', b'e', b'l', b'd']);
+        packet.extend_from_slice(&[4, b'_', b'u', b'd', b'p', 5, b'l', b'o', b'c', b'a', b'l', 0]);
+        packet.extend_from_slice(&[0, TYPE_PTR as u8, 0, CLASS_IN as u8, 0, 0, 0, 120, 0, 4]);
+        packet.extend_from_slice(&[1, b'a', 0xc0, 12]);
+        // Named by a pointer to the first name, with a target ending in "_udp.local"
+        packet.extend_from_slice(&[0xc0, 12, 0, TYPE_SRV as u8, 0, CLASS_IN as u8, 0, 0, 0, 120, 0, 10]);
+        packet.extend_from_slice(&[0, 0, 0, 0, 0x5e, 0xb2, 1, b't', 0xc0, 21]);
+
+        let message = Message::parse(&packet).unwrap();
+        assert_eq!(message.records.len(), 2);
+        assert_eq!(message.records[0].name, "_elemeld._udp.local");
+        match message.records[0].data {
+            RecordData::Ptr(ref instance) => assert_eq!(instance, "a._elemeld._udp.local"),
+            _ => panic!("Expected a PTR record"),
+        }
+
+        assert_eq!(message.records[1].name, "_elemeld._udp.local");
+        match message.records[1].data {
+            RecordData::Srv(port, ref target) => {
+                assert_eq!(port, 24242);
+                assert_eq!(target, "t._udp.local");
+            },
+            _ => panic!("Expected an SRV record"),
+        }
+    }
+
+    #[test]
+    fn rejects_truncated_packets() {
+        let mut packet = Packet::new(0x8400);
+        packet.srv("a-1._elemeld._udp.local", 24242, "a-1.local");
+        let packet = packet.finish();
+        for len in 0..packet.len() {
+            assert!(Message::parse(&packet[..len]).is_none(), "Parsed {} bytes", len);
+        }
+    }
+
+    #[test]
+    fn rejects_pointer_loops() {
+        let mut packet = header(1, 0);
+        packet.extend_from_slice(&[0xc0, 12, 0, TYPE_PTR as u8, 0, CLASS_IN as u8]);
+        assert!(Message::parse(&packet).is_none());
+    }
+
+    #[test]
+    fn rejects_labels_past_the_end() {
+        let mut packet = header(1, 0);
+        packet.extend_from_slice(&[10, b'a', b'b', 0, 0, TYPE_PTR as u8, 0, CLASS_IN as u8]);
+        assert!(Message::parse(&packet).is_none());
+    }
+
+    #[test]
+    fn ignores_unknown_and_malformed_address_records() {
+        let mut packet = header(0, 2);
+        packet.extend_from_slice(&[1, b'a', 0, 0, TYPE_A as u8, 0, CLASS_IN as u8, 0, 0, 0, 120, 0, 2, 1, 2]);
+        packet.extend_from_slice(&[1, b'a', 0, 0, 99, 0, CLASS_IN as u8, 0, 0, 0, 120, 0, 1, 7]);
+        let message = Message::parse(&packet).unwrap();
+        assert_eq!(message.records.len(), 2);
+        for record in &message.records {
+            match record.data {
+                RecordData::Other => (),
+                _ => panic!("Expected an ignored record"),
+            }
+        }
+    }
+}
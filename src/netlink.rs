@@ -0,0 +1,151 @@
+use libc;
+use mio::*;
+
+use std::{io, mem, ptr};
+use std::os::unix::io::RawFd;
+
+const NETLINK_ROUTE: libc::c_int = 0;
+
+const RTMGRP_LINK: u32 = 0x1;
+const RTMGRP_IPV4_IFADDR: u32 = 0x10;
+const RTMGRP_IPV6_IFADDR: u32 = 0x100;
+
+const RTM_NEWLINK: u16 = 16;
+const RTM_DELLINK: u16 = 17;
+const RTM_NEWADDR: u16 = 20;
+const RTM_DELADDR: u16 = 21;
+
+#[repr(C)]
+struct SockAddrNl {
+    nl_family: libc::sa_family_t,
+    nl_pad: libc::c_ushort,
+    nl_pid: u32,
+    nl_groups: u32,
+}
+
+#[repr(C)]
+#[allow(dead_code)]
+struct NlMsgHeader {
+    nlmsg_len: u32,
+    nlmsg_type: u16,
+    nlmsg_flags: u16,
+    nlmsg_seq: u32,
+    nlmsg_pid: u32,
+}
+
+/// Listens for links and addresses coming and going over rtnetlink
+pub struct NetlinkWatch {
+    fd: RawFd,
+}
+
+impl NetlinkWatch {
+    pub fn open() -> io::Result<Self> {
+        let fd = unsafe { libc::socket(
+            libc::AF_NETLINK,
+            libc::SOCK_RAW | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC,
+            NETLINK_ROUTE
+        ) };
+
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        // Closes the socket if binding fails
+        let watch = NetlinkWatch { fd: fd };
+
+        let addr = SockAddrNl {
+            nl_family: libc::AF_NETLINK as libc::sa_family_t,
+            nl_pad: 0,
+            nl_pid: 0,
+            nl_groups: RTMGRP_LINK | RTMGRP_IPV4_IFADDR | RTMGRP_IPV6_IFADDR,
+        };
+
+        let res = unsafe { libc::bind(
+            fd,
+            &addr as *const SockAddrNl as *const libc::sockaddr,
+            mem::size_of::<SockAddrNl>() as libc::socklen_t
+        ) };
+
+        if res != 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(watch)
+    }
+
+    /// Read every pending notification, returning whether
+    /// any link or address was added or removed
+    pub fn recv(&self) -> io::Result<bool> {
+        let mut changed = false;
+        let mut buf = [0u8; 8192];
+        loop {
+            let len = unsafe { libc::recv(
+                self.fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len(), 0
+            ) };
+
+            if len < 0 {
+                let err = io::Error::last_os_error();
+                match err.raw_os_error() {
+                    Some(libc::EAGAIN) => return Ok(changed),
+                    // Notifications were dropped, so assume the worst
+                    Some(libc::ENOBUFS) => changed = true,
+                    _ => return Err(err),
+                }
+            } else {
+                changed |= has_change(&buf[..len as usize]);
+            }
+        }
+    }
+}
+
+/// Look through a datagram of netlink messages for link or address changes
+fn has_change(buf: &[u8]) -> bool {
+    let header_len = mem::size_of::<NlMsgHeader>();
+    let mut offset = 0;
+    while offset + header_len <= buf.len() {
+        let header = unsafe {
+            let mut header: NlMsgHeader = mem::zeroed();
+            ptr::copy_nonoverlapping(buf[offset..].as_ptr(), &mut header as *mut NlMsgHeader as *mut u8, header_len);
+            header
+        };
+
+        match header.nlmsg_type {
+            RTM_NEWLINK | RTM_DELLINK | RTM_NEWADDR | RTM_DELADDR => return true,
+            _ => (),
+        }
+
+        let len = header.nlmsg_len as usize;
+        if len < header_len {
+            break;
+        }
+
+        // Messages are padded to 4 bytes
+        offset += (len + 3) & !3;
+    }
+
+    false
+}
+
+impl Drop for NetlinkWatch {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.fd) };
+    }
+}
+
+/*
+ * FIXME(Future):
+ * Method delegation: https://github.com/rust-lang/rfcs/pull/1406
+ */
+impl Evented for NetlinkWatch {
+    fn register(&self, selector: &mut Selector, token: Token, interest: EventSet, opts: PollOpt) -> io::Result<()> {
+        selector.register(self.fd, token, interest, opts)
+    }
+
+    fn reregister(&self, selector: &mut Selector, token: Token, interest: EventSet, opts: PollOpt) -> io::Result<()> {
+        selector.reregister(self.fd, token, interest, opts)
+    }
+
+    fn deregister(&self, selector: &mut Selector) -> io::Result<()> {
+        selector.deregister(self.fd)
+    }
+}
@@ -0,0 +1,134 @@
+use io::*;
+use cluster::Index;
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+/// Number of unanswered probes before an address is considered dead
+const MAX_MISSED: u32 = 3;
+
+/// Tracks which advertised addresses of each peer are reachable,
+/// so focused events can be sent to the best live address
+pub struct Routes {
+    routes: HashMap<SocketAddr, Route>,
+    pending: HashMap<u64, (SocketAddr, Instant)>,
+    next_id: u64,
+}
+
+struct Route {
+    rtt: Option<Duration>,
+    missed: u32,
+}
+
+impl Route {
+    fn is_alive(&self) -> bool {
+        self.rtt.is_some() && self.missed < MAX_MISSED
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RouteStatus {
+    pub screen: Index,
+    pub addr: String,
+    pub alive: bool,
+    pub rtt_us: Option<u64>,
+}
+
+impl Routes {
+    pub fn new() -> Self {
+        Routes {
+            routes: HashMap::new(),
+            pending: HashMap::new(),
+            next_id: 0,
+        }
+    }
+
+    /// Start a new probe round, returning the ping to send to each address.
+    /// Any probe still unanswered from the last round counts as missed.
+    pub fn probe(&mut self, addrs: &[SocketAddr]) -> Vec<(SocketAddr, NetEvent)> {
+        for (_, (addr, _)) in self.pending.drain() {
+            if let Some(route) = self.routes.get_mut(&addr) {
+                let was_alive = route.is_alive();
+                route.missed += 1;
+                if was_alive && !route.is_alive() {
+                    warn!("Route to {} is down", addr);
+                }
+            }
+        }
+
+        // Forget addresses that are no longer advertised
+        let stale = self.routes.keys()
+            .filter(|addr| !addrs.contains(addr))
+            .cloned()
+            .collect::<Vec<_>>();
+
+        for addr in stale {
+            self.routes.remove(&addr);
+        }
+
+        let now = Instant::now();
+        addrs.iter().map(|addr| {
+            self.routes.entry(*addr).or_insert(Route { rtt: None, missed: 0 });
+
+            let id = self.next_id;
+            self.next_id = self.next_id.wrapping_add(1);
+            self.pending.insert(id, (*addr, now));
+            (*addr, NetEvent::Ping(id))
+        }).collect()
+    }
+
+    /// Record a reply to a probe
+    pub fn pong(&mut self, id: u64) {
+        if let Some((addr, sent)) = self.pending.remove(&id) {
+            if let Some(route) = self.routes.get_mut(&addr) {
+                let rtt = sent.elapsed();
+                if !route.is_alive() {
+                    info!("Route to {} is up ({} us)", addr, as_micros(&rtt));
+                } else {
+                    debug!("Route to {}: {} us", addr, as_micros(&rtt));
+                }
+
+                route.rtt = Some(rtt);
+                route.missed = 0;
+            }
+        }
+    }
+
+    /// Choose the live address with the lowest round trip time,
+    /// falling back to the first advertised address
+    pub fn best<'a>(&self, addrs: &'a [SocketAddr]) -> Option<&'a SocketAddr> {
+        addrs.iter()
+            .filter_map(|addr| match self.routes.get(addr) {
+                Some(route) if route.is_alive() => route.rtt.map(|rtt| (addr, rtt)),
+                _ => None,
+            })
+            .min_by_key(|&(_, rtt)| rtt)
+            .map(|(addr, _)| addr)
+            .or(addrs.first())
+    }
+
+    pub fn is_alive(&self, addr: &SocketAddr) -> bool {
+        self.routes.get(addr).map_or(false, |route| route.is_alive())
+    }
+
+    /// Whether an address has stopped answering probes,
+    /// as opposed to not having been probed enough yet
+    pub fn is_dead(&self, addr: &SocketAddr) -> bool {
+        self.routes.get(addr).map_or(false, |route| route.missed >= MAX_MISSED)
+    }
+
+    pub fn status(&self, screen: Index, addr: &SocketAddr) -> RouteStatus {
+        let route = self.routes.get(addr);
+        RouteStatus {
+            screen: screen,
+            addr: format!("{}", addr),
+            alive: route.map_or(false, |route| route.is_alive()),
+            rtt_us: route.and_then(|route| route.rtt).map(|rtt| as_micros(&rtt)),
+        }
+    }
+}
+
+fn as_micros(duration: &Duration) -> u64 {
+    duration.as_secs() * 1_000_000 + duration.subsec_nanos() as u64 / 1_000
+}
@@ -0,0 +1,156 @@
+use util;
+
+use std::{fs, io, cmp};
+use std::io::Read;
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Identifies when a focus or layout event was sent, so copies that arrive
+/// late or out of order can be dropped. Epochs order a host's restarts and
+/// sequence numbers order events within an epoch.
+#[derive(Clone, Copy, Serialize, Deserialize, Debug)]
+pub struct Stamp {
+    pub host_id: u64,
+    pub epoch: u64,
+    pub seq: u64,
+}
+
+/// Which events are ordered against each other
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum Stream {
+    Focus,
+    Layout,
+}
+
+pub struct Sequence {
+    host_id: u64,
+    epoch: u64,
+    next: Cell<u64>,
+    /// Latest (epoch, seq) applied from each host. Host ids are saved per
+    /// host name, so hosts sharing a home directory still differ.
+    applied: HashMap<(u64, Stream), (u64, u64)>,
+}
+
+impl Sequence {
+    pub fn new(host_id: u64) -> Self {
+        Sequence {
+            host_id: host_id,
+            epoch: next_epoch(),
+            next: Cell::new(0),
+            applied: HashMap::new(),
+        }
+    }
+
+    pub fn stamp(&self) -> Stamp {
+        let seq = self.next.get();
+        self.next.set(seq + 1);
+        Stamp {
+            host_id: self.host_id,
+            epoch: self.epoch,
+            seq: seq,
+        }
+    }
+
+    /// Record an event, returning false if a newer one from
+    /// the same host has already been applied
+    pub fn accept(&mut self, stream: Stream, stamp: &Stamp) -> bool {
+        let key = (stamp.host_id, stream);
+        let current = (stamp.epoch, stamp.seq);
+        if let Some(&applied) = self.applied.get(&key) {
+            if current <= applied {
+                return false;
+            }
+        }
+
+        self.applied.insert(key, current);
+        true
+    }
+}
+
+/// Bump the epoch saved in the app dir, so events sent after a restart are
+/// never mistaken for ones sent before it. The epoch is at least the time
+/// in seconds, so it still increases if the saved one is lost or can't be saved.
+fn next_epoch() -> u64 {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)
+        .map(|time| time.as_secs())
+        .unwrap_or(0);
+
+    let path = match util::user_app_dir("elemeld") {
+        Some(dir) => dir.join("epoch"),
+        None => return now,
+    };
+
+    let epoch = cmp::max(read_epoch(&path).unwrap_or(0) + 1, now);
+    if let Err(err) = util::write_private(&path, format!("{}\n", epoch).as_bytes()) {
+        warn!("Failed to save epoch to {}: {}", path.display(), err);
+    }
+
+    epoch
+}
+
+fn read_epoch(path: &Path) -> io::Result<u64> {
+    let mut contents = String::new();
+    try!(try!(fs::File::open(path)).read_to_string(&mut contents));
+    contents.trim().parse().map_err(|_| io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Invalid epoch in {}", path.display())
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Sequence, Stamp, Stream};
+    use std::cell::Cell;
+    use std::collections::HashMap;
+
+    fn sequence() -> Sequence {
+        Sequence {
+            host_id: 1,
+            epoch: 1,
+            next: Cell::new(0),
+            applied: HashMap::new(),
+        }
+    }
+
+    fn stamp(host_id: u64, epoch: u64, seq: u64) -> Stamp {
+        Stamp { host_id: host_id, epoch: epoch, seq: seq }
+    }
+
+    #[test]
+    fn stamps_increase() {
+        let sequence = sequence();
+        let first = sequence.stamp();
+        let second = sequence.stamp();
+        assert_eq!((first.host_id, first.epoch), (1, 1));
+        assert!(second.seq > first.seq);
+    }
+
+    #[test]
+    fn drops_stale_and_repeated_stamps() {
+        let mut sequence = sequence();
+        assert!(sequence.accept(Stream::Focus, &stamp(2, 5, 3)));
+        assert!(!sequence.accept(Stream::Focus, &stamp(2, 5, 3)));
+        assert!(!sequence.accept(Stream::Focus, &stamp(2, 5, 2)));
+        assert!(sequence.accept(Stream::Focus, &stamp(2, 5, 4)));
+    }
+
+    #[test]
+    fn orders_across_epochs() {
+        let mut sequence = sequence();
+        assert!(sequence.accept(Stream::Focus, &stamp(2, 5, 100)));
+        // The host restarted, so its sequence numbers start again
+        assert!(sequence.accept(Stream::Focus, &stamp(2, 6, 0)));
+        assert!(!sequence.accept(Stream::Focus, &stamp(2, 5, 101)));
+    }
+
+    #[test]
+    fn keeps_hosts_and_streams_apart() {
+        let mut sequence = sequence();
+        assert!(sequence.accept(Stream::Focus, &stamp(2, 5, 10)));
+        assert!(sequence.accept(Stream::Layout, &stamp(2, 5, 1)));
+        assert!(sequence.accept(Stream::Focus, &stamp(3, 1, 0)));
+        assert!(!sequence.accept(Stream::Layout, &stamp(2, 5, 0)));
+    }
+}
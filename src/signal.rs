@@ -0,0 +1,77 @@
+use nix::sys::signal::{self, SigAction, SigHandler, SigNum, SigSet, SA_RESTART};
+use nix::fcntl::{O_NONBLOCK, O_CLOEXEC};
+use nix::unistd;
+use nix::errno::EAGAIN;
+use nix;
+
+use mio::*;
+
+use std::io;
+use std::os::unix::io::RawFd;
+use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+
+pub use nix::sys::signal::{SIGHUP, SIGINT, SIGTERM};
+
+/// Write end of the self-pipe, as signal handlers can't carry state
+static PIPE: AtomicUsize = ATOMIC_USIZE_INIT;
+
+extern fn handle_signal(signum: SigNum) {
+    let fd = PIPE.load(Ordering::SeqCst) as RawFd;
+    let _ = unistd::write(fd, &[signum as u8]);
+}
+
+/// Delivers signals through a pipe so they can
+/// be handled from the event loop
+pub struct Signals {
+    read_fd: RawFd,
+    write_fd: RawFd,
+}
+
+impl Signals {
+    pub fn open(signals: &[SigNum]) -> io::Result<Self> {
+        let (read_fd, write_fd) = try!(unistd::pipe2(O_NONBLOCK | O_CLOEXEC));
+        PIPE.store(write_fd as usize, Ordering::SeqCst);
+
+        let action = SigAction::new(SigHandler::Handler(handle_signal), SA_RESTART, SigSet::empty());
+        for &signum in signals {
+            try!(unsafe { signal::sigaction(signum, &action) });
+        }
+
+        Ok(Signals { read_fd: read_fd, write_fd: write_fd })
+    }
+
+    pub fn recv(&self) -> io::Result<Option<SigNum>> {
+        let mut buf = [0u8; 1];
+        match unistd::read(self.read_fd, &mut buf) {
+            Ok(1) => Ok(Some(buf[0] as SigNum)),
+            Ok(_) => Ok(None),
+            Err(nix::Error::Sys(EAGAIN)) => Ok(None),
+            Err(err) => Err(io::Error::from(err)),
+        }
+    }
+}
+
+impl Drop for Signals {
+    fn drop(&mut self) {
+        let _ = unistd::close(self.read_fd);
+        let _ = unistd::close(self.write_fd);
+    }
+}
+
+/*
+ * FIXME(Future):
+ * Method delegation: https://github.com/rust-lang/rfcs/pull/1406
+ */
+impl Evented for Signals {
+    fn register(&self, selector: &mut Selector, token: Token, interest: EventSet, opts: PollOpt) -> io::Result<()> {
+        selector.register(self.read_fd, token, interest, opts)
+    }
+
+    fn reregister(&self, selector: &mut Selector, token: Token, interest: EventSet, opts: PollOpt) -> io::Result<()> {
+        selector.reregister(self.read_fd, token, interest, opts)
+    }
+
+    fn deregister(&self, selector: &mut Selector) -> io::Result<()> {
+        selector.deregister(self.read_fd)
+    }
+}
@@ -3,9 +3,12 @@ use nix::errno::Errno;
 use nix::unistd::{gethostname};
 use nix::sys::socket::{Ipv4Addr, Ipv6Addr, sockaddr_in, sockaddr_in6};
 
-use std::{mem, ptr, net, env};
-use libc::{strlen, getifaddrs, freeifaddrs, AF_INET, AF_INET6};
-use std::path::PathBuf;
+use std::{io, mem, ptr, net, env, fs};
+use std::io::{Read, Write};
+use std::ffi::CStr;
+use libc::{self, strlen, getifaddrs, freeifaddrs, if_nametoindex, AF_INET, AF_INET6};
+use std::os::unix::io::RawFd;
+use std::path::{Path, PathBuf};
 
 /// Obtain the host's name
 pub fn get_host_name() -> Result<String, nix::Error> {
@@ -16,8 +19,18 @@ pub fn get_host_name() -> Result<String, nix::Error> {
     })
 }
 
-/// Obtain all of the host's IP addresses
-pub fn get_host_ips() -> Result<Vec<net::IpAddr>, nix::Error> {
+/// An IP address assigned to one of the host's network interfaces
+#[derive(Clone, Debug)]
+pub struct HostAddr {
+    pub interface: String,
+    pub index: u32,
+    pub ip: net::IpAddr,
+    pub scope_id: u32,
+}
+
+/// Obtain all of the host's IP addresses along with the interface
+/// they are assigned to and their IPv6 scope id
+pub fn get_host_addrs() -> Result<Vec<HostAddr>, nix::Error> {
     let mut addrs = Vec::new();
 
     unsafe {
@@ -29,14 +42,26 @@ pub fn get_host_ips() -> Result<Vec<net::IpAddr>, nix::Error> {
         let mut ptr = list;
         while !ptr.is_null() {
             if !(*ptr).ifa_addr.is_null() {
+                let interface = CStr::from_ptr((*ptr).ifa_name).to_string_lossy().into_owned();
+                let index = if_nametoindex((*ptr).ifa_name);
                 match (*(*ptr).ifa_addr).sa_family as i32 {
                     AF_INET => {
                         let sa = (*ptr).ifa_addr as *const sockaddr_in;
-                        addrs.push(net::IpAddr::V4(Ipv4Addr((*sa).sin_addr).to_std()));
+                        addrs.push(HostAddr {
+                            interface: interface,
+                            index: index,
+                            ip: net::IpAddr::V4(Ipv4Addr((*sa).sin_addr).to_std()),
+                            scope_id: 0,
+                        });
                     },
                     AF_INET6 => {
                         let sa = (*ptr).ifa_addr as *const sockaddr_in6;
-                        addrs.push(net::IpAddr::V6(Ipv6Addr((*sa).sin6_addr).to_std()));
+                        addrs.push(HostAddr {
+                            interface: interface,
+                            index: index,
+                            ip: net::IpAddr::V6(Ipv6Addr((*sa).sin6_addr).to_std()),
+                            scope_id: (*sa).sin6_scope_id,
+                        });
                     },
                     _ => (),
                 }
@@ -50,6 +75,66 @@ pub fn get_host_ips() -> Result<Vec<net::IpAddr>, nix::Error> {
     Ok(addrs)
 }
 
+/// Select the interface outgoing IPv4 multicast packets are sent from
+pub fn set_multicast_if_v4(fd: RawFd, addr: &net::Ipv4Addr) -> Result<(), nix::Error> {
+    let addr = libc::in_addr { s_addr: u32::from(*addr).to_be() };
+    setsockopt(fd, libc::IPPROTO_IP, libc::IP_MULTICAST_IF, &addr)
+}
+
+/// Select the interface outgoing IPv6 multicast packets are sent from
+pub fn set_multicast_if_v6(fd: RawFd, index: u32) -> Result<(), nix::Error> {
+    setsockopt(fd, libc::IPPROTO_IPV6, libc::IPV6_MULTICAST_IF, &index)
+}
+
+fn setsockopt<T>(fd: RawFd, level: libc::c_int, name: libc::c_int, val: &T) -> Result<(), nix::Error> {
+    let res = unsafe { libc::setsockopt(
+        fd, level, name,
+        val as *const T as *const libc::c_void,
+        mem::size_of::<T>() as libc::socklen_t
+    ) };
+
+    if res != 0 {
+        return Err(nix::Error::Sys(Errno::last()));
+    }
+
+    Ok(())
+}
+
+/// Map an IPv4 socket address into the IPv4-mapped IPv6 range so
+/// it can be used with a dual-stack IPv6 socket
+pub fn to_ipv6_mapped(addr: &net::SocketAddr) -> net::SocketAddr {
+    match *addr {
+        net::SocketAddr::V4(addr) => net::SocketAddr::V6(net::SocketAddrV6::new(
+            addr.ip().to_ipv6_mapped(), addr.port(), 0, 0
+        )),
+        addr => addr,
+    }
+}
+
+/// Reverse of `to_ipv6_mapped`: unwrap IPv4-mapped IPv6 addresses
+/// received on a dual-stack IPv6 socket
+pub fn from_ipv6_mapped(addr: &net::SocketAddr) -> net::SocketAddr {
+    match *addr {
+        net::SocketAddr::V6(addr) => {
+            let s = addr.ip().segments();
+            if s[0..5] == [0, 0, 0, 0, 0] && s[5] == 0xffff {
+                net::SocketAddr::V4(net::SocketAddrV4::new(net::Ipv4Addr::new(
+                    (s[6] >> 8) as u8, s[6] as u8, (s[7] >> 8) as u8, s[7] as u8
+                ), addr.port()))
+            } else {
+                net::SocketAddr::V6(addr)
+            }
+        },
+        addr => addr,
+    }
+}
+
+/// Check if an IPv6 address is link-local (fe80::/10) and
+/// so requires a scope id to be routable
+pub fn is_link_local_v6(ip: &net::Ipv6Addr) -> bool {
+    ip.segments()[0] & 0xffc0 == 0xfe80
+}
+
 // Obtain the directory for storing application data
 pub fn user_app_dir(name: &str) -> Option<PathBuf> {
     env::home_dir().map(|base| {
@@ -64,3 +149,111 @@ pub fn user_app_dir(name: &str) -> Option<PathBuf> {
     //      ~/Library/Application Support/<name>
     //
 }
+
+/// Obtain the directory for runtime files such as sockets,
+/// falling back to a per-user directory in /tmp
+pub fn user_runtime_dir(name: &str) -> PathBuf {
+    match env::var_os("XDG_RUNTIME_DIR") {
+        Some(base) => PathBuf::from(base).join(name),
+        None => env::temp_dir().join(format!("{}-{}", name, unsafe { libc::getuid() })),
+    }
+}
+
+/// Create a directory only the current user can use, refusing one that
+/// already exists but belongs to someone else (eg. a squatted path in /tmp)
+pub fn create_private_dir(path: &Path) -> io::Result<()> {
+    use std::os::unix::fs::{DirBuilderExt, MetadataExt, PermissionsExt};
+
+    match fs::DirBuilder::new().mode(0o700).create(path) {
+        Ok(_) => (),
+        Err(ref err) if err.kind() == io::ErrorKind::AlreadyExists => (),
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => {
+            try!(fs::DirBuilder::new().recursive(true).mode(0o700).create(path));
+        },
+        Err(err) => return Err(err),
+    }
+
+    // Not following symlinks, so the checks apply to the path itself
+    let metadata = try!(fs::symlink_metadata(path));
+    if !metadata.is_dir() || metadata.uid() != unsafe { libc::getuid() } {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("{} is not a directory owned by the current user", path.display())
+        ));
+    }
+
+    if metadata.mode() & 0o077 != 0 {
+        try!(fs::set_permissions(path, fs::Permissions::from_mode(0o700)));
+    }
+
+    Ok(())
+}
+
+/// Write a file only the current user can read. A new file is written
+/// and renamed into place, so neither an existing file's permissions
+/// nor a symlink planted at the path are ever written through.
+pub fn write_private(path: &Path, contents: &[u8]) -> io::Result<()> {
+    use std::os::unix::fs::OpenOptionsExt;
+    use std::os::unix::io::AsRawFd;
+
+    if let Some(dir) = path.parent() {
+        try!(create_private_dir(dir));
+    }
+
+    let tmp = path.with_extension(format!("tmp-{}", unsafe { libc::getpid() }));
+    let _ = fs::remove_file(&tmp);
+    let result = fs::OpenOptions::new()
+        .write(true).create_new(true)
+        .mode(0o600).custom_flags(libc::O_NOFOLLOW)
+        .open(&tmp)
+        .and_then(|mut file| {
+            if unsafe { libc::fchmod(file.as_raw_fd(), 0o600) } != 0 {
+                return Err(io::Error::last_os_error());
+            }
+
+            file.write_all(contents)
+        })
+        .and_then(|_| fs::rename(&tmp, path));
+
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp);
+    }
+
+    result
+}
+
+/// Read random bytes from the kernel's entropy pool
+pub fn random_bytes(buf: &mut [u8]) -> io::Result<()> {
+    let mut file = try!(fs::File::open("/dev/urandom"));
+    file.read_exact(buf)
+}
+
+/// Obtain a random identifier for this host, persisted in the app dir
+/// so it stays the same across restarts. The file is named after the
+/// host, so hosts sharing a home directory get different ids.
+pub fn host_id() -> io::Result<u64> {
+    let app_dir = try!(user_app_dir("elemeld").ok_or(io::Error::new(
+        io::ErrorKind::NotFound, "No home directory"
+    )));
+
+    let name = try!(get_host_name().map_err(|err| io::Error::new(io::ErrorKind::Other, err)));
+    let path = app_dir.join(format!("host_id-{}", name));
+    if let Ok(mut file) = fs::File::open(&path) {
+        let mut id = String::new();
+        try!(file.read_to_string(&mut id));
+        if let Ok(id) = u64::from_str_radix(id.trim(), 16) {
+            return Ok(id);
+        }
+
+        warn!("Invalid host id in {}, generating a new one", path.display());
+    }
+
+    let mut buf = [0u8; 8];
+    try!(random_bytes(&mut buf));
+    let id = buf.iter().fold(0u64, |id, &byte| id << 8 | byte as u64);
+
+    try!(fs::create_dir_all(&app_dir));
+    let mut file = try!(fs::File::create(&path));
+    try!(write!(file, "{:016x}\n", id));
+    Ok(id)
+}
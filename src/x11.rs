@@ -6,33 +6,63 @@ use x11_dl::xlib;
 use x11_dl::xinput2;
 use x11_dl::xtest;
 use x11_dl::xfixes;
+use x11_dl::xrandr;
 use mio::*;
 
-use std::{io, ptr, mem};
-use std::cell::Cell;
-use std::ffi::CString;
+use std::{io, ptr, mem, slice};
+use std::cell::{Cell, RefCell};
+use std::ffi::{CStr, CString};
+
+// XFixes barrier directions (directions the cursor may pass through)
+const BARRIER_POSITIVE_X: i32 = 1 << 0;
+const BARRIER_POSITIVE_Y: i32 = 1 << 1;
+const BARRIER_NEGATIVE_X: i32 = 1 << 2;
+const BARRIER_NEGATIVE_Y: i32 = 1 << 3;
 
 pub struct X11Interface {
     xlib: xlib::Xlib,
     xinput2: xinput2::XInput2,
     xtest: xtest::Xf86vmode,
     xfixes: xfixes::XFixes,
+    xrandr: xrandr::Xrandr,
     xfixes_event_base: i32,
+    xinput2_opcode: i32,
 
     display: *mut xlib::Display,
     root: xlib::Window,
     clipboard: xlib::Atom,
 
-    last_pos: Cell<(i32, i32)>,
+    accelerated: bool,
+    motion_remainder: Cell<(f64, f64)>,
     cursor_grabbed: Cell<bool>,
+    barriers: Cell<Barriers>,
+    /// Pointer barriers need XInput 2.3, otherwise edges
+    /// are found by checking the cursor position on motion
+    barriers_supported: bool,
+    barrier_ids: RefCell<Vec<xfixes::PointerBarrier>>,
+    /// Virtual devices that input injected through XTest comes from
+    xtest_devices: RefCell<Vec<i32>>,
+    /// Devices reporting absolute positions (eg. tablets), whose
+    /// motion is found by diffing the cursor position instead
+    absolute_devices: RefCell<Vec<i32>>,
+    last_pos: Cell<(i32, i32)>,
+    /// Where the cursor was last warped to by remote input
+    warped_pos: Cell<Option<(i32, i32)>>,
+    pointer_locked: Cell<bool>,
+    escape_keys: RefCell<Vec<u64>>,
+    /// Keys held down while the keyboard is grabbed
+    held_keys: RefCell<Vec<u64>>,
 }
 
 impl X11Interface {
-    pub fn open() -> Self {
+    /// When `accelerated` is false, motion deltas are read from the
+    /// device before the X server applies pointer acceleration
+    pub fn open(accelerated: bool) -> Self {
         let xlib = xlib::Xlib::open().unwrap();
         let xtest = xtest::Xf86vmode::open().unwrap();
         let xinput2 = xinput2::XInput2::open().unwrap();
         let xfixes = xfixes::XFixes::open().unwrap();
+        let xrandr = xrandr::Xrandr::open().unwrap();
 
         let display = unsafe { (xlib.XOpenDisplay)(ptr::null()) };
         if display.is_null() {
@@ -40,7 +70,7 @@ impl X11Interface {
         }
 
         // Query XInput2
-        unsafe {
+        let xinput2_opcode = unsafe {
             let mut major_opcode = mem::uninitialized();
             let mut first_event = mem::uninitialized();
             let mut first_error = mem::uninitialized();
@@ -52,7 +82,9 @@ impl X11Interface {
             ) == xlib::False {
                 panic!("Failed to query XInputExtension");
             };
-        }
+
+            major_opcode
+        };
 
         // Query XFixes
         let xfixes_event_base = unsafe {
@@ -73,27 +105,54 @@ impl X11Interface {
             display, CString::new("CLIPBOARD").unwrap().as_ptr(), 0
         ) };
 
-        let host = X11Interface {
+        let mut host = X11Interface {
             xlib: xlib,
             xtest: xtest,
             xinput2: xinput2,
             xfixes: xfixes,
+            xrandr: xrandr,
             xfixes_event_base: xfixes_event_base,
+            xinput2_opcode: xinput2_opcode,
 
             display: display,
             root: root,
             clipboard: clipboard,
 
-            last_pos: Cell::new((0, 0)),
+            accelerated: accelerated,
+            motion_remainder: Cell::new((0.0, 0.0)),
             cursor_grabbed: Cell::new(false),
+            barriers: Cell::new(Barriers::default()),
+            barriers_supported: false,
+            barrier_ids: RefCell::new(Vec::new()),
+            xtest_devices: RefCell::new(Vec::new()),
+            absolute_devices: RefCell::new(Vec::new()),
+            last_pos: Cell::new((0, 0)),
+            warped_pos: Cell::new(None),
+            pointer_locked: Cell::new(false),
+            escape_keys: RefCell::new(ESCAPE_KEYS.to_vec()),
+            held_keys: RefCell::new(Vec::new()),
         };
 
         host.init();
         host
     }
 
-    fn init(&self) {
-        // Initialize last_pos for computing cursor deltas
+    fn init(&mut self) {
+        // Raw events need XInput 2.0 and barrier events 2.3
+        unsafe {
+            let mut major = 2;
+            let mut minor = 3;
+            if (self.xinput2.XIQueryVersion)(self.display, &mut major, &mut minor) != xlib::Success as i32 {
+                panic!("XInput 2 is required");
+            }
+
+            self.barriers_supported = (major, minor) >= (2, 3);
+            if !self.barriers_supported {
+                warn!("XInput {}.{} has no pointer barriers, polling the cursor for edges instead", major, minor);
+            }
+        }
+
+        self.refresh_devices();
         self.last_pos.set(self.cursor_pos());
 
         // Setup selection events
@@ -103,11 +162,24 @@ impl X11Interface {
         // Setup raw motion events
         let mut mask = [0u8; (xinput2::XI_LASTEVENT as usize + 7) / 8];
         xinput2::XISetMask(&mut mask, xinput2::XI_RawMotion);
+        if self.barriers_supported {
+            xinput2::XISetMask(&mut mask, xinput2::XI_BarrierHit);
+        }
+        xinput2::XISetMask(&mut mask, xinput2::XI_RawKeyPress);
+        xinput2::XISetMask(&mut mask, xinput2::XI_RawButtonPress);
+
+        // Devices are looked up again when they're added or removed
+        let mut hierarchy_mask = [0u8; (xinput2::XI_LASTEVENT as usize + 7) / 8];
+        xinput2::XISetMask(&mut hierarchy_mask, xinput2::XI_HierarchyChanged);
 
         let mut events = [xinput2::XIEventMask {
             deviceid: xinput2::XIAllMasterDevices,
             mask_len: mask.len() as i32,
             mask: &mut mask[0] as *mut u8,
+        }, xinput2::XIEventMask {
+            deviceid: xinput2::XIAllDevices,
+            mask_len: hierarchy_mask.len() as i32,
+            mask: &mut hierarchy_mask[0] as *mut u8,
         }];
 
         self.select_events(self.root, &mut events);
@@ -141,23 +213,179 @@ impl X11Interface {
         ) };
     }
 
-    fn recv_generic_event(&self, cookie: xlib::XGenericEventCookie) -> Option<HostEvent> {
-        // FIXME: Assert XInput2 extension in cookie.extension
-        assert_eq!(cookie.evtype, xinput2::XI_RawMotion);
+    /// Find the ids of the devices matching `filter`
+    fn query_devices<F>(&self, filter: F) -> Vec<i32> where
+        F: Fn(&xinput2::XIDeviceInfo) -> bool
+    {
+        unsafe {
+            let mut count = 0;
+            let info = (self.xinput2.XIQueryDevice)(self.display, xinput2::XIAllDevices, &mut count);
+            if info.is_null() {
+                return Vec::new();
+            }
+
+            let devices = slice::from_raw_parts(info, count as usize).iter()
+                .filter(|device| filter(device))
+                .map(|device| device.deviceid)
+                .collect();
+
+            (self.xinput2.XIFreeDeviceInfo)(info);
+            devices
+        }
+    }
+
+    /// Find the XTest and absolute devices, whose input is handled differently
+    fn refresh_devices(&self) {
+        *self.xtest_devices.borrow_mut() = self.query_devices(|device| unsafe {
+            CStr::from_ptr(device.name).to_string_lossy().contains("XTEST")
+        });
+
+        *self.absolute_devices.borrow_mut() = self.query_devices(|device| unsafe {
+            slice::from_raw_parts(device.classes, device.num_classes as usize).iter()
+                .filter(|&&class| (*class)._type == xinput2::XIValuatorClass)
+                .map(|&class| &*(class as *const xinput2::XIValuatorClassInfo))
+                .any(|valuator| valuator.number < 2 && valuator.mode == xinput2::XIModeAbsolute)
+        });
+    }
+
+    /// Whether the cursor image is fully transparent
+    fn cursor_hidden(&self) -> bool {
+        unsafe {
+            let image = (self.xfixes.XFixesGetCursorImage)(self.display);
+            if image.is_null() {
+                return false;
+            }
+
+            let len = (*image).width as usize * (*image).height as usize;
+            let pixels = slice::from_raw_parts((*image).pixels, len);
+            let hidden = pixels.iter().all(|&pixel| pixel & 0xff000000 == 0);
+            (self.xlib.XFree)(image as *mut _);
+            hidden
+        }
+    }
+
+    /// Whether a raw event came from real hardware rather than XTest
+    fn is_hardware(&self, event: &xinput2::XIRawEvent) -> bool {
+        !self.xtest_devices.borrow().contains(&event.sourceid)
+    }
+
+    fn recv_generic_event(&self, cookie: &xlib::XGenericEventCookie) -> Option<HostEvent> {
+        if cookie.extension != self.xinput2_opcode {
+            warn!("Unexpected generic event from extension: {}", cookie.extension);
+            return None;
+        }
+
+        match cookie.evtype {
+            xinput2::XI_RawMotion => {
+                let event = unsafe { &*(cookie.data as *const xinput2::XIRawEvent) };
+                if self.is_hardware(event) {
+                    self.recv_raw_motion_event(event)
+                } else { None }
+            },
+            xinput2::XI_RawKeyPress | xinput2::XI_RawButtonPress => {
+                let event = unsafe { &*(cookie.data as *const xinput2::XIRawEvent) };
+                if self.is_hardware(event) {
+                    Some(HostEvent::Activity)
+                } else { None }
+            },
+            xinput2::XI_BarrierHit => {
+                let event = unsafe { &*(cookie.data as *const xinput2::XIBarrierEvent) };
+                self.recv_barrier_event(event)
+            },
+            xinput2::XI_HierarchyChanged => {
+                debug!("Input devices changed");
+                self.refresh_devices();
+                None
+            },
+            evtype => {
+                warn!("Unexpected XInput2 event: {}", evtype);
+                None
+            },
+        }
+    }
+
+    /// Read the motion deltas straight from the raw event's valuators.
+    /// Raw events aren't clamped to the screen edges and aren't generated
+    /// by XWarpPointer, so the cursor never needs to be queried or re-centred.
+    fn recv_raw_motion_event(&self, event: &xinput2::XIRawEvent) -> Option<HostEvent> {
+        if self.absolute_devices.borrow().contains(&event.sourceid) {
+            return self.recv_absolute_motion_event();
+        }
+
+        let mask = unsafe { slice::from_raw_parts(
+            event.valuators.mask, event.valuators.mask_len as usize
+        ) };
+
+        let values = if self.accelerated {
+            event.valuators.values
+        } else {
+            event.raw_values
+        };
 
+        // Values are only present for valuators set in the mask
+        let (mut dx, mut dy) = (0.0, 0.0);
+        let mut index = 0;
+        for valuator in 0..2 {
+            if (valuator as usize) < mask.len() * 8 && xinput2::XIMaskIsSet(mask, valuator) {
+                let value = unsafe { *values.offset(index) };
+                match valuator {
+                    0 => dx = value,
+                    _ => dy = value,
+                }
+                index += 1;
+            }
+        }
+
+        // Carry sub-pixel motion over to the next event
+        let (rem_x, rem_y) = self.motion_remainder.get();
+        let (dx, dy) = (dx + rem_x, dy + rem_y);
+        let (int_x, int_y) = (dx.trunc(), dy.trunc());
+        self.motion_remainder.set((dx - int_x, dy - int_y));
+
+        Some(self.motion_event(int_x as i32, int_y as i32))
+    }
+
+    /// Absolute valuators hold positions rather than deltas, so
+    /// diff the cursor position, re-centring it while grabbed
+    fn recv_absolute_motion_event(&self) -> Option<HostEvent> {
         let (x, y) = self.cursor_pos();
         let (last_x, last_y) = self.last_pos.get();
-        let (dx, dy) = (x - last_x, y - last_y);
         self.last_pos.set((x, y));
 
-        // Lock cursor to center when grabbed
         if self.cursor_grabbed.get() {
             let (width, height) = self.screen_size();
-            let (x, y) = (width / 2, height / 2);
-            self.send_position_event(PositionEvent { x: x, y: y });
+            self.send_position_event(PositionEvent { x: width / 2, y: height / 2 });
         }
 
-        Some(HostEvent::Motion(MotionEvent { dx: dx, dy: dy }))
+        Some(self.motion_event(x - last_x, y - last_y))
+    }
+
+    /// Without pointer barriers, report motion pushing against
+    /// an edge that should have one as if it hit the barrier
+    fn motion_event(&self, dx: i32, dy: i32) -> HostEvent {
+        let barriers = self.barriers.get();
+        if !self.barriers_supported && !self.cursor_grabbed.get() && barriers != Barriers::default() {
+            let (x, y) = self.cursor_pos();
+            let (width, height) = self.screen_size();
+            if (barriers.left && x <= 0 && dx < 0) ||
+                (barriers.right && x >= width - 1 && dx > 0) ||
+                (barriers.top && y <= 0 && dy < 0) ||
+                (barriers.bottom && y >= height - 1 && dy > 0)
+            {
+                return HostEvent::Barrier(BarrierEvent { x: x, y: y, dx: dx, dy: dy });
+            }
+        }
+
+        HostEvent::Motion(MotionEvent { dx: dx, dy: dy })
+    }
+
+    fn recv_barrier_event(&self, event: &xinput2::XIBarrierEvent) -> Option<HostEvent> {
+        Some(HostEvent::Barrier(BarrierEvent {
+            x: event.root_x as i32,
+            y: event.root_y as i32,
+            dx: event.dx.round() as i32,
+            dy: event.dy.round() as i32,
+        }))
     }
 
     fn recv_button_event(&self, event: xlib::XButtonEvent, state: bool) -> Option<HostEvent> {
@@ -169,6 +397,21 @@ impl X11Interface {
 
     fn recv_key_event(&self, event: xlib::XKeyEvent, state: bool) -> Option<HostEvent> {
         let keysym = unsafe { (self.xlib.XKeycodeToKeysym)(self.display, event.keycode as u8, 0) };
+
+        // Handled here so it works even if the focused peer is gone
+        let mut held = self.held_keys.borrow_mut();
+        if state {
+            if !held.contains(&keysym) { held.push(keysym) }
+            let escape = self.escape_keys.borrow();
+            if !escape.is_empty() && escape.contains(&keysym) &&
+                escape.iter().all(|key| held.contains(key))
+            {
+                return Some(HostEvent::Escape);
+            }
+        } else {
+            held.retain(|&key| key != keysym);
+        }
+
         Some(HostEvent::Key(KeyEvent {
             key: keysym,
             state: state,
@@ -194,19 +437,115 @@ impl X11Interface {
         }
     }
 
+    fn translate_event(&self, event: xlib::XEvent) -> Option<HostEvent> {
+        let event_type = event.get_type();
+
+        // Standard events
+        match event_type {
+            xlib::GenericEvent => {
+                let mut cookie: xlib::XGenericEventCookie = From::from(event);
+                if unsafe { (self.xlib.XGetEventData)(self.display, &mut cookie) } == xlib::False {
+                    return None;
+                }
+
+                let event = self.recv_generic_event(&cookie);
+                unsafe { (self.xlib.XFreeEventData)(self.display, &mut cookie) };
+                return event;
+            },
+            xlib::ButtonPress => return self.recv_button_event(From::from(event), true),
+            xlib::ButtonRelease => return self.recv_button_event(From::from(event), false),
+            xlib::KeyPress => return self.recv_key_event(From::from(event), true),
+            xlib::KeyRelease => return self.recv_key_event(From::from(event), false),
+            xlib::MappingNotify => return None,
+            _ => (),
+        };
+
+        // XFixes selection events
+        match event_type - self.xfixes_event_base {
+            xfixes::XFixesSelectionNotify => return self.recv_selection_event(From::from(event)),
+            _ => (),
+        };
+
+        warn!("Unexpected X11 event: {}", event_type);
+        None
+    }
+
+    /// Explain why a grab failed. Other clients usually hold their grabs
+    /// briefly (eg. while a menu is open), so the caller retries later.
+    fn grab_result(&self, device: &str, status: i32) -> io::Result<()> {
+        let reason = match status {
+            xlib::GrabSuccess => return Ok(()),
+            xlib::AlreadyGrabbed => "already grabbed by another client",
+            xlib::GrabFrozen => "frozen by another client's grab",
+            xlib::GrabNotViewable => "root window not viewable",
+            xlib::GrabInvalidTime => "invalid time",
+            _ => "unknown error",
+        };
+
+        Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!("Failed to grab the {}: {}", device, reason)
+        ))
+    }
+
+    /// Pixels per millimetre of the primary output, or the first
+    /// connected one that reports its physical size
+    fn output_density(&self) -> Option<(f64, f64)> {
+        unsafe {
+            let resources = (self.xrandr.XRRGetScreenResourcesCurrent)(self.display, self.root);
+            if resources.is_null() {
+                return None;
+            }
+
+            let primary = (self.xrandr.XRRGetOutputPrimary)(self.display, self.root);
+            let outputs = slice::from_raw_parts((*resources).outputs, (*resources).noutput as usize);
+            let mut density = None;
+            for output in Some(primary).into_iter().filter(|&output| output != 0).chain(outputs.iter().cloned()) {
+                let info = (self.xrandr.XRRGetOutputInfo)(self.display, resources, output);
+                if info.is_null() {
+                    continue;
+                }
+
+                let (mm_width, mm_height) = ((*info).mm_width as f64, (*info).mm_height as f64);
+                if (*info).connection == xrandr::RR_Connected && (*info).crtc != 0 && mm_width > 0.0 && mm_height > 0.0 {
+                    let crtc = (self.xrandr.XRRGetCrtcInfo)(self.display, resources, (*info).crtc);
+                    if !crtc.is_null() {
+                        // The physical size isn't rotated along with the mode
+                        let rotated = (*crtc).rotation & (xrandr::RR_Rotate_90 | xrandr::RR_Rotate_270) != 0;
+                        let (mm_width, mm_height) = if rotated { (mm_height, mm_width) } else { (mm_width, mm_height) };
+                        density = Some(((*crtc).width as f64 / mm_width, (*crtc).height as f64 / mm_height));
+                        (self.xrandr.XRRFreeCrtcInfo)(crtc);
+                    }
+                }
+
+                (self.xrandr.XRRFreeOutputInfo)(info);
+                if density.is_some() {
+                    break;
+                }
+            }
+
+            (self.xrandr.XRRFreeScreenResources)(resources);
+            density
+        }
+    }
+
     fn send_position_event(&self, event: PositionEvent) {
+        self.last_pos.set((event.x, event.y));
+        self.warped_pos.set(Some((event.x, event.y)));
         unsafe {
-            self.last_pos.set((event.x, event.y));
             (self.xlib.XWarpPointer)(self.display, 0, self.root, 0, 0, 0, 0, event.x, event.y);
             (self.xlib.XFlush)(self.display);
         };
     }
 
+    /// Inject device motion (rather than warping) so applications
+    /// reading relative or raw motion see it too
     fn send_motion_event(&self, event: MotionEvent) {
+        let (last_x, last_y) = self.last_pos.get();
+        self.last_pos.set((last_x + event.dx, last_y + event.dy));
+        self.warped_pos.set(None);
         unsafe {
-            let (last_x, last_y) = self.last_pos.get();
-            self.last_pos.set((last_x + event.dx, last_y + event.dy));
-            (self.xlib.XWarpPointer)(self.display, 0, 0, 0, 0, 0, 0, event.dx, event.dy);
+            (self.xtest.XTestFakeRelativeMotionEvent)(self.display, event.dx, event.dy, xlib::CurrentTime);
             (self.xlib.XFlush)(self.display);
         };
     }
@@ -236,6 +575,21 @@ impl HostInterface for X11Interface {
         }
     }
 
+    /// Servers usually pin the core screen size to 96 DPI, so the
+    /// physical size comes from the outputs' EDID through XRandR
+    fn screen_size_mm(&self) -> (i32, i32) {
+        match self.output_density() {
+            Some((density_x, density_y)) => {
+                let (width, height) = self.screen_size();
+                ((width as f64 / density_x) as i32, (height as f64 / density_y) as i32)
+            },
+            None => unsafe {
+                let screen = &*(self.xlib.XDefaultScreenOfDisplay)(self.display);
+                (screen.mwidth, screen.mheight)
+            },
+        }
+    }
+
     fn cursor_pos(&self) -> (i32, i32) {
         unsafe {
             let mut root = mem::uninitialized();
@@ -255,21 +609,24 @@ impl HostInterface for X11Interface {
         }
     }
 
-    fn grab_cursor(&self) {
+    fn grab_cursor(&self) -> io::Result<()> {
         if self.cursor_grabbed.get() {
-            return;
+            return Ok(());
         }
 
-        unsafe {
+        let status = unsafe {
             let mask = xlib::ButtonPressMask | xlib::ButtonReleaseMask;
             (self.xlib.XGrabPointer)(
                 self.display, self.root, xlib::True, mask as u32,
                 xlib::GrabModeAsync, xlib::GrabModeAsync, 0, 0, xlib::CurrentTime
-            );
-            (self.xfixes.XFixesHideCursor)(self.display, self.root);
+            )
         };
 
+        try!(self.grab_result("pointer", status));
+
+        unsafe { (self.xfixes.XFixesHideCursor)(self.display, self.root) };
         self.cursor_grabbed.set(true);
+        Ok(())
     }
 
     fn ungrab_cursor(&self) {
@@ -285,44 +642,91 @@ impl HostInterface for X11Interface {
         self.cursor_grabbed.set(false);
     }
 
-    fn grab_keyboard(&self) {
-        unsafe { (self.xlib.XGrabKeyboard)(
+    fn grab_keyboard(&self) -> io::Result<()> {
+        let status = unsafe { (self.xlib.XGrabKeyboard)(
             self.display, self.root, xlib::True,
             xlib::GrabModeAsync, xlib::GrabModeAsync, xlib::CurrentTime
         ) };
+
+        self.grab_result("keyboard", status)
     }
 
     fn ungrab_keyboard(&self) {
         unsafe { (self.xlib.XUngrabKeyboard)(self.display, xlib::CurrentTime) };
+        self.held_keys.borrow_mut().clear();
     }
 
-    fn recv_event(&self) -> Option<HostEvent> {
-        let num_events = unsafe { (self.xlib.XPending)(self.display) };
-        if num_events <= 0 {
-            return None;
+    fn set_barriers(&self, barriers: Barriers) {
+        if self.barriers.get() == barriers {
+            return;
         }
 
-        let event = self.next_event();
-        let event_type = event.get_type();
+        self.barriers.set(barriers);
+        if !self.barriers_supported {
+            return;
+        }
 
-        // Standard events
-        match event_type {
-            xlib::GenericEvent => return self.recv_generic_event(From::from(event)),
-            xlib::ButtonPress => return self.recv_button_event(From::from(event), true),
-            xlib::ButtonRelease => return self.recv_button_event(From::from(event), false),
-            xlib::KeyPress => return self.recv_key_event(From::from(event), true),
-            xlib::KeyRelease => return self.recv_key_event(From::from(event), false),
-            xlib::MappingNotify => return None,
-            _ => (),
-        };
+        let mut ids = self.barrier_ids.borrow_mut();
+        for id in ids.drain(..) {
+            unsafe { (self.xfixes.XFixesDestroyPointerBarrier)(self.display, id) };
+        }
 
-        // XFixes selection events
-        match event_type - self.xfixes_event_base {
-            xfixes::XFixesSelectionNotify => return self.recv_selection_event(From::from(event)),
-            _ => (),
-        };
+        // The cursor stops a pixel short of a barrier, so the right and bottom
+        // ones go just past the screen to keep the last column and row reachable
+        let (right, bottom) = self.screen_size();
+        let edges = [
+            (barriers.top, (0, 0, right, 0), BARRIER_POSITIVE_Y),
+            (barriers.right, (right, 0, right, bottom), BARRIER_NEGATIVE_X),
+            (barriers.bottom, (0, bottom, right, bottom), BARRIER_NEGATIVE_Y),
+            (barriers.left, (0, 0, 0, bottom), BARRIER_POSITIVE_X),
+        ];
+
+        // Each barrier only lets the cursor back into the screen
+        for &(enabled, (x1, y1, x2, y2), directions) in &edges {
+            if enabled {
+                ids.push(unsafe { (self.xfixes.XFixesCreatePointerBarrier)(
+                    self.display, self.root, x1, y1, x2, y2, directions, 0, ptr::null_mut()
+                ) });
+            }
+        }
+
+        unsafe { (self.xlib.XFlush)(self.display) };
+    }
+
+    /// Check if a local application has locked the pointer the way games
+    /// do: the cursor is hidden and something other than our input moved
+    /// it away from where it was last warped (eg. back to the centre of a
+    /// window). Hiding the cursor alone, as video players do, isn't enough.
+    /// Asking for the cursor image doesn't disturb a grab the way a probing
+    /// grab would.
+    fn pointer_locked(&self) -> bool {
+        if self.cursor_grabbed.get() || !self.cursor_hidden() {
+            self.pointer_locked.set(false);
+            return false;
+        }
+
+        if !self.pointer_locked.get() {
+            let pos = self.cursor_pos();
+            let moved = self.warped_pos.get().map_or(false, |warped| warped != pos);
+            self.pointer_locked.set(moved);
+        }
+
+        self.pointer_locked.get()
+    }
+
+    fn set_escape_keys(&self, keys: &[u64]) {
+        *self.escape_keys.borrow_mut() = keys.to_vec();
+    }
+
+    fn recv_event(&self) -> Option<HostEvent> {
+        // Skip events that aren't host events without
+        // leaving the rest of the queue behind
+        while unsafe { (self.xlib.XPending)(self.display) } > 0 {
+            if let Some(event) = self.translate_event(self.next_event()) {
+                return Some(event);
+            }
+        }
 
-        warn!("Unexpected X11 event: {}", event_type);
         None
     }
 
@@ -357,6 +761,7 @@ impl Evented for X11Interface {
 
 impl Drop for X11Interface {
     fn drop(&mut self) {
+        self.set_barriers(Barriers::default());
         unsafe { (self.xlib.XCloseDisplay)(self.display) };
     }
 }
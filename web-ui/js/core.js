@@ -1,5 +1,6 @@
 (function(window, document, E) {
     var gridPad = 5;
+    var apiVersion = 1;
 
     function vectorAdd(a, b) {
         return a.map((val, dim) => val + b[dim]);
@@ -106,23 +107,24 @@
                 target.dragEnd(e);
             }
 
-            // Update the cluster
-            socket.send(JSON.stringify({
-                Screens: this.cluster.screens.map((screen, id) => {
-                    if (this.screens[id]) {
-                        // Update the edges
-                        var edges = this.screens[id].edges;
-                        screen.edges = {
-                            left: edges[0][0] ? edges[0][0].id : null,
-                            right: edges[0][1] ? edges[0][1].id : null,
-                            top: edges[1][0] ? edges[1][0].id : null,
-                            bottom: edges[1][1] ? edges[1][1].id : null,
-                        };
+            // Update the edges that changed
+            this.cluster.screens.forEach((screen, id) => {
+                if (!this.screens[id]) return;
+
+                var edges = this.screens[id].edges;
+                var neighbours = {
+                    left: edges[0][0] ? edges[0][0].id : null,
+                    right: edges[0][1] ? edges[0][1].id : null,
+                    top: edges[1][0] ? edges[1][0].id : null,
+                    bottom: edges[1][1] ? edges[1][1].id : null,
+                };
+
+                for (var edge in neighbours) {
+                    if (screen.edges[edge] !== neighbours[edge]) {
+                        this.request({SetEdge: {screen: id, edge: edge, neighbour: neighbours[edge]}});
                     }
-
-                    return screen;
-                }),
-            }));
+                }
+            });
 
             delete this.focuses[e.id];
         };
@@ -166,18 +168,59 @@
         elem.addEventListener('touchend', touchEvent.bind(this, dragEnd), false);
 
         // Network events
+        this.socket = socket;
+        this.nextId = 1;
         socket.onmessage = (e) => {
             var obj = JSON.parse(e.data);
             var type = Object.keys(obj)[0];
-            var event = obj[type];
+            var msg = obj[type];
             switch(type) {
-            case "Cluster":
-                this.replaceCluster(event);
+            case "Hello":
+                if (msg.version !== apiVersion) {
+                    this.showError("Unsupported API version " + msg.version);
+                }
+                break;
+            case "Response":
+                if (msg.result.Error) this.showError(msg.result.Error);
+                break;
+            case "Event":
+                this.handleEvent(msg);
                 break;
             }
         };
     }
 
+    Canvas.prototype.request = function(method) {
+        this.socket.send(JSON.stringify({
+            id: this.nextId++,
+            method: method,
+        }));
+    };
+
+    Canvas.prototype.handleEvent = function(obj) {
+        var type = Object.keys(obj)[0];
+        var event = obj[type];
+        switch(type) {
+        case "Cluster":
+            this.replaceCluster(event);
+            break;
+        case "Routes":
+            this.updateRoutes(event);
+            break;
+        case "Focus":
+            this.updateFocus(event);
+            break;
+        case "PeerOnline":
+        case "PeerOffline":
+            var screen = this.screens[event.screen];
+            if (screen) screen.setOnline(type === "PeerOnline");
+            break;
+        case "Error":
+            this.showError(event);
+            break;
+        }
+    };
+
     Canvas.prototype.setPos = function(pos) {
         this.pos = pos;
         this.view.style.transform = 'translate(' + this.pos[0] + 'px,' + this.pos[1] + 'px)';
@@ -206,6 +249,35 @@
         });
         this.screens = [];
         this.addScreens(cluster, cluster.local_screen, this.getCenter());
+        this.updateFocus(this.focus || {screen: cluster.focused_screen});
+    };
+
+    Canvas.prototype.updateRoutes = function(routes) {
+        this.screens.forEach((screen) => {
+            screen.setRoutes(routes.filter((route) => route.screen === screen.id));
+        });
+    };
+
+    Canvas.prototype.updateFocus = function(focus) {
+        this.focus = focus;
+        this.screens.forEach((screen) => {
+            screen.elem.classList.toggle('focused', screen.id === focus.screen);
+        });
+    };
+
+    Canvas.prototype.showError = function(msg) {
+        if (!this.error) {
+            this.error = E('div', {
+                className: 'error',
+                parent: this.elem,
+            });
+        }
+
+        this.error.textContent = msg;
+        clearTimeout(this.errorTimeout);
+        this.errorTimeout = setTimeout(() => {
+            this.error.textContent = '';
+        }, 5000);
     };
 
     Canvas.prototype.addScreens = function(cluster, id, pos) {
@@ -280,6 +352,11 @@
             })]
         });
 
+        this.routes = E('ul', {
+            className: 'screen-routes',
+            parent: this.elem,
+        });
+
         this.setSize(params.size);
         this.setPos(params.pos);
         this.edges = [[null, null], [null, null]];
@@ -297,6 +374,21 @@
         this.elem.style.height = this.size[1] + 'px';
     };
 
+    Screen.prototype.setOnline = function(online) {
+        this.elem.classList.toggle('offline', !online);
+    };
+
+    Screen.prototype.setRoutes = function(routes) {
+        this.routes.textContent = '';
+        routes.forEach((route) => {
+            E('li', {
+                className: ['screen-route', route.alive ? 'alive' : 'dead'],
+                textContent: route.addr + (route.alive ? ' (' + (route.rtt_us / 1000).toFixed(1) + ' ms)' : ' (unreachable)'),
+                parent: this.routes,
+            });
+        });
+    };
+
     Screen.prototype.closest = function(screens) {
         // TODO: Probably should take into account different screen sizes
         var pos = this.pos;
@@ -385,7 +477,10 @@
         this.connectClosest(e.source.getScreens());
     };
 
-    var socket = new WebSocket("ws://127.0.0.1:3012");
+    // Connect back to the daemon serving the page, passing
+    // along the session token it was opened with
+    var token = /[?&]token=([^&]*)/.exec(location.search);
+    var socket = new WebSocket("ws://" + location.host + "/" + (token ? "?token=" + token[1] : ""));
     socket.onopen = function(e) {
         new Canvas(document.querySelector('.canvas'), socket);
     };